@@ -1,15 +1,53 @@
 use super::util;
 
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::process;
+use std::thread;
+use std::env;
+use std::collections::{HashMap, HashSet, BTreeMap};
+
 use rusqlite::Connection;
 use clap::{value_t, values_t};
+use nodes::Config;
 
-pub fn rm(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+pub fn rm(conn: &Connection, config: &Config, args: &clap::ArgMatches) -> i32 {
     let nodes = util::gather_nodes(&args, "id");
     if nodes.is_empty() {
-        println!("No valid ids given");
+        info!("No valid ids given");
         return -1;
     }
 
+    if args.is_present("dry_run") {
+        return rm_dry_run(conn, config, nodes);
+    }
+
+    if !args.is_present("yes") {
+        // without a terminal there is no one to ask
+        if !termion::is_tty(&io::stdin()) {
+            eprintln!("Not deleting without confirmation, use --yes");
+            return -1;
+        }
+
+        let question = format!("Delete {} nodes?", nodes.len());
+        let confirmed = termion::get_tty().and_then(|tty| {
+            let mut output = tty.try_clone()?;
+            util::confirm(io::BufReader::new(tty), &mut output, &question)
+        });
+
+        match confirmed {
+            Ok(true) => (),
+            Ok(false) => {
+                eprintln!("{}", util::Error::Aborted);
+                return -1;
+            }, Err(err) => {
+                eprintln!("Failed to read confirmation: {}", err);
+                return -2;
+            }
+        }
+    }
+
     match util::delete_range(&conn, &nodes) {
         Ok(num) => (nodes.len() - num) as i32,
         Err(err) => {
@@ -19,91 +57,494 @@ pub fn rm(conn: &Connection, args: &clap::ArgMatches) -> i32 {
     }
 }
 
-pub fn ls(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+// Prints the nodes rm would delete
+fn rm_dry_run(conn: &Connection, config: &Config, ids: Vec<u32>) -> i32 {
+    let width = if termion::is_tty(&io::stdout()) {
+        util::terminal_size().map(|size| size.0 as usize).unwrap_or(80)
+    } else {
+        80
+    };
+
+    let strip = config.strip_heading_markers();
+    let list_args = util::QueryArgs {
+        preorder: util::Order::Asc,
+        postorder: util::Order::Asc,
+        count: None,
+        pattern: None,
+        archived: util::ArchivedFilter::All,
+        sort: Some(util::Sort::ID),
+        ids: Some(ids),
+        content_prefix: if strip { None } else { Some(width + 1) },
+        pinned_first: false,
+    };
+
+    let mut count = 0;
+    let res = util::iter_nodes(&conn, &list_args, |node| {
+        let summary = util::node_summary(node.content, 1, width, strip);
+        println!("{}:\t{}", node.id, summary);
+        count += 1;
+    });
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        return -2;
+    }
+
+    info!("Would delete {} nodes", count);
+    0
+}
+
+pub fn ls(conn: &Connection, config: &Config, storage: &str,
+        args: &clap::ArgMatches) -> i32 {
     // number of lines to output as node preview
     let mut lines = value_t!(args, "lines", u32).unwrap_or(1);
     if args.is_present("full") {
         lines = 0xFFFFFFFFu32;
     }
 
-    // number of nodes to show
-    let width = util::terminal_size().0 as usize;
-    let args = util::extract_list_args(&args, true, false);
-    util::iter_nodes(&conn, &args, |node| {
-        let summary = util::node_summary(&node.content, lines as usize, width);
-        if lines == 1 {
-            println!("{}:\t{}", node.id, summary)
-        } else {
-            println!("{}:\t{}", node.id, summary);
+    // width of the summary lines
+    let width = if args.is_present("width") {
+        value_t!(args, "width", usize).unwrap_or_else(|e| e.exit())
+    } else {
+        output_width()
+    };
+
+    let group_by_tag = args.value_of("group_by") == Some("tag");
+    let untagged = args.is_present("untagged");
+    let print0 = args.is_present("print0");
+    let pager = !args.is_present("no_pager");
+    let end = if print0 { "\0" } else { "\n" };
+    let format = args.value_of("format").map(|f| f.to_string())
+        .or_else(|| if print0 { Some("%i".to_string()) } else { None });
+    let counts = args.is_present("count");
+    let strip = config.strip_heading_markers();
+    // whether to note how many nodes were cut off by the limit
+    let show_total = args.is_present("total") ||
+        (format.is_none() && termion::is_tty(&io::stdout()));
+    let mut args = util::extract_list_args(&args, config, storage, true, false);
+    // counting needs the whole content, the title might not be at
+    // the start when stripping
+    if lines == 1 && format.is_none() && !counts && !strip {
+        // one more char than shown to know whether it was cut off
+        args.content_prefix = Some(width + 1);
+    }
+
+    util::with_pager(config, pager, |out| {
+        if group_by_tag {
+            return match ls_by_tag(conn, out, &args, lines as usize, width,
+                    strip, untagged) {
+                Ok(()) => Ok(0),
+                Err(util::Error::IO(err)) => Err(err),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    Ok(-2)
+                }
+            };
         }
-    });
 
-    0
+        // stop writing after the first error, e.g. when the pager was closed
+        let mut res = Ok(());
+        let mut shown = 0;
+        let query = util::iter_nodes(&conn, &args, |node| {
+            if res.is_err() {
+                return;
+            }
+
+            shown += 1;
+            res = match &format {
+                Some(format) => write!(out, "{}{}", util::format_node(format, node), end),
+                None if counts => {
+                    let summary = util::node_summary(&node.content,
+                        lines as usize, width, strip);
+                    let (chars, words) = util::content_counts(node.content);
+                    writeln!(out, "{}:\t{}w {}c\t{}", node.id, words, chars, summary)
+                }, None => {
+                    let summary = util::node_summary(&node.content,
+                        lines as usize, width, strip);
+                    writeln!(out, "{}:\t{}", node.id, summary)
+                }
+            };
+        });
+
+        res?;
+        if let Err(err) = query {
+            eprintln!("{}", err);
+            return Ok(-2);
+        }
+
+        if show_total && args.count == Some(shown) {
+            match util::count_nodes(&conn, &args) {
+                Ok(total) if total > shown =>
+                    writeln!(out, "(showing {} of {})", shown, total)?,
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return Ok(-2);
+                }
+            }
+        }
+
+        Ok(0)
+    })
 }
 
-// TODO: use transaction i guess
-pub fn create(conn: &Connection, args: &clap::ArgMatches) -> i32 {
-    let res = util::create(&conn, args.value_of("content"));
+// Width of the output when no explicit width was given.
+// When not writing to a terminal, use a stable default so that
+// output in scripts does not depend on the current terminal
+fn output_width() -> usize {
+    if let Some(cols) = env::var("COLUMNS").ok()
+            .and_then(|c| c.parse::<usize>().ok()) {
+        cols
+    } else if termion::is_tty(&io::stdout()) {
+        util::terminal_size().map(|size| size.0 as usize).unwrap_or(80)
+    } else {
+        80
+    }
+}
+
+pub fn recent(conn: &Connection, config: &Config, args: &clap::ArgMatches) -> i32 {
+    let count = value_t!(args, "count", usize).unwrap_or_else(|e| e.exit());
+    let width = output_width();
+    let strip = config.strip_heading_markers();
+    let list_args = util::QueryArgs {
+        // most recently viewed ones, the latest printed last like ls
+        preorder: util::Order::Desc,
+        postorder: util::Order::Asc,
+        count: Some(count),
+        pattern: None,
+        archived: util::ArchivedFilter::Active,
+        sort: Some(util::Sort::Viewed),
+        ids: None,
+        content_prefix: if strip { None } else { Some(width + 1) },
+        pinned_first: false,
+    };
+
+    let res = util::iter_nodes(&conn, &list_args, |node| {
+        let summary = util::node_summary(&node.content, 1, width, strip);
+        println!("{}:\t{}", node.id, summary);
+    });
+
     if let Err(err) = res {
         eprintln!("{}", err);
         return -2;
     }
 
-    let id = res.unwrap();
-    if args.is_present("tags") {
-        let tags: Vec<&str> = args.values_of("tags").unwrap().collect();
-        util::add_tags(&conn, &[id], &tags).unwrap();
-    }
-
-    println!("{}", id);
     0
 }
 
-pub fn output(conn: &Connection, args: &clap::ArgMatches) -> i32 {
-    let id = value_t!(args, "id", u32).unwrap_or_else(|e| e.exit());
-    let r = conn.query_row(
-        "SELECT content FROM nodes WHERE id = ?1", &[id],
-        |row| {
-            println!("{}", &row.get_raw(0).as_str().unwrap());
-            Ok(())
+// Lists the nodes grouped under each of their tags, a node appears
+// once for every tag it has. Groups are ordered by tag name.
+fn ls_by_tag(conn: &Connection, out: &mut dyn Write, args: &util::QueryArgs,
+        lines: usize, width: usize, strip: bool, untagged: bool)
+        -> Result<(), util::Error> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut without = Vec::new();
+    util::iter_nodes(&conn, &args, |node| {
+        let summary = util::node_summary(&node.content, lines, width, strip);
+        let line = format!("{}:\t{}", node.id, summary);
+        if node.tags.is_empty() {
+            without.push(line);
+        } else {
+            for tag in &node.tags {
+                groups.entry(tag.to_string()).or_default().push(line.clone());
+            }
         }
-    );
+    })?;
 
-    if let Err(e) = r {
-        if e == rusqlite::Error::QueryReturnedNoRows {
-            println!("No such node: {}", id);
+    let mut first = true;
+    let mut print_group = |header: &str, lines: &[String]| -> io::Result<()> {
+        if !first {
+            writeln!(out)?;
+        }
+        first = false;
+        writeln!(out, "[{}]", header)?;
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    };
+
+    for (tag, lines) in &groups {
+        print_group(tag, lines)?;
+    }
+
+    if untagged && !without.is_empty() {
+        print_group("untagged", &without)?;
+    }
+
+    Ok(())
+}
+
+pub fn create(conn: &Connection, options: &util::EditOptions,
+        args: &clap::ArgMatches) -> i32 {
+    let mut content = args.value_of("content").map(|c| c.to_string());
+    if content.is_none() && !termion::is_tty(&io::stdin()) {
+        // piped content, e.g. `echo note | nodes create`
+        let mut text = String::new();
+        if let Err(err) = io::stdin().read_to_string(&mut text) {
+            eprintln!("Failed to read stdin: {}", err);
             return -1;
         }
+        content = Some(text);
+    }
 
-        println!("{}", e);
-        return -2;
+    // no node is created if tagging it fails
+    let res = util::transaction(&conn, || {
+        let id = util::create(&conn, options, content.as_deref())?;
+        if let Some(tags) = args.values_of("tags") {
+            let mut tags: Vec<&str> = tags.collect();
+            tags.sort();
+            tags.dedup();
+            util::add_tags(&conn, &[id], &tags)?;
+        }
+        Ok(id)
+    });
+
+    match res {
+        Ok(id) => {
+            println!("{}", id);
+            0
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
     }
+}
 
-    // Strictly speaking we should use a transaction here, but it's
-    // not really a problem in the end
-    let query = "
-        UPDATE nodes
-        SET viewed = CURRENT_TIMESTAMP
-        WHERE id = ?1";
-    conn.execute(query, &[&id]).unwrap();
+// Pipes the given content into the given renderer program.
+// Returns the output of the renderer for the given content
+fn render_content(renderer: &[String], content: &str) -> io::Result<Vec<u8>> {
+    let mut child = process::Command::new(&renderer[0])
+        .args(renderer[1..].iter())
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
 
-    0
+    // written from another thread, the renderer might block on its
+    // output before reading all input
+    let mut stdin = child.stdin.take().unwrap();
+    let mut content = content.to_string();
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    let writer = thread::spawn(move || stdin.write_all(content.as_bytes()));
+
+    let output = child.wait_with_output()?;
+    writer.join().unwrap()?;
+    Ok(output.stdout)
+}
+
+// Header with the metadata of a node, for output --meta
+fn write_meta(out: &mut dyn Write, node: &util::ExportNode) -> io::Result<()> {
+    writeln!(out, "id:       {}", node.id)?;
+    writeln!(out, "priority: {}", node.priority)?;
+    writeln!(out, "archived: {}", if node.archived { "yes" } else { "no" })?;
+    if node.tags.is_empty() {
+        writeln!(out, "tags:     -")?;
+    } else {
+        writeln!(out, "tags:     {}", node.tags.join(", "))?;
+    }
+    if let Some(created) = &node.created {
+        writeln!(out, "created:  {}", created)?;
+    }
+    writeln!(out, "edited:   {}", node.edited)?;
+    writeln!(out, "viewed:   {}", node.viewed)?;
+    writeln!(out)
 }
 
-pub fn edit(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+pub fn output(conn: &Connection, config: &Config, args: &clap::ArgMatches)
+        -> i32 {
+    let ids = util::gather_nodes(&args, "id");
+    let raw = args.is_present("raw");
+    let delimiter = args.value_of("delimiter").unwrap();
+    let renderer = if args.is_present("render") {
+        config.markdown_renderer()
+    } else {
+        None
+    };
+
+    let meta = args.is_present("meta");
+    util::with_pager(config, !args.is_present("no_pager"), |out| {
+        let mut missing = false;
+        let mut first = true;
+        for id in ids {
+            let node = match util::fetch_node_full(&conn, id) {
+                Ok(node) => node,
+                Err(util::Error::InvalidNode(_)) => {
+                    info!("No such node: {}", id);
+                    missing = true;
+                    continue;
+                }, Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(-2);
+                }
+            };
+            let content = &node.content;
+
+            if !first {
+                if raw {
+                    write!(out, "{}", delimiter)?;
+                } else {
+                    writeln!(out, "{}", delimiter)?;
+                }
+            }
+            first = false;
+
+            if meta {
+                write_meta(out, &node)?;
+            }
+
+            match renderer {
+                Some(renderer) => match render_content(renderer, &content) {
+                    Ok(rendered) => out.write_all(&rendered)?,
+                    Err(err) => {
+                        eprintln!("Failed to run '{}': {}", renderer[0], err);
+                        writeln!(out, "{}", content)?;
+                    }
+                }, None if raw => out.write_all(content.as_bytes())?,
+                None => writeln!(out, "{}", content)?,
+            }
+
+            if args.is_present("no_touch") {
+                continue;
+            }
+
+            // Strictly speaking we should use a transaction here, but it's
+            // not really a problem in the end
+            let query = "
+                UPDATE nodes
+                SET viewed = CURRENT_TIMESTAMP
+                WHERE id = ?1";
+            conn.execute(query, &[&id]).unwrap();
+        }
+
+        Ok(if missing { -1 } else { 0 })
+    })
+}
+
+/// Parses a line range in the form 'first:last'.
+pub fn parse_line_range(range: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("Invalid line range '{}', expected first:last", range);
+    let mut parts = range.splitn(2, ':');
+    let first = parts.next().and_then(|p| p.parse::<usize>().ok());
+    let last = parts.next().and_then(|p| p.parse::<usize>().ok());
+    match (first, last) {
+        (Some(first), Some(last)) if first > 0 && first <= last =>
+            Ok((first, last)),
+        _ => Err(invalid()),
+    }
+}
+
+pub fn edit(conn: &Connection, options: &util::EditOptions,
+        args: &clap::ArgMatches) -> i32 {
     let id = value_t!(args, "id", u32).unwrap_or_else(|e| e.exit());
-    if let Err(e) = util::edit(&conn, id) {
+    let lines = args.value_of("lines").map(|l| parse_line_range(l).unwrap());
+    if let Err(e) = util::edit(&conn, options, id, lines) {
         eprintln!("{}", e);
         return -6;
     }
     0
 }
 
+// Appends or prepends the given text to a node
+pub fn append(conn: &Connection, args: &clap::ArgMatches, prepend: bool) -> i32 {
+    let id = value_t!(args, "id", u32).unwrap_or_else(|e| e.exit());
+    let text = match args.value_of("content") {
+        Some(content) => content.to_string(),
+        None => {
+            let mut text = String::new();
+            if let Err(err) = io::stdin().read_to_string(&mut text) {
+                eprintln!("Failed to read stdin: {}", err);
+                return -1;
+            }
+            text
+        }
+    };
+
+    if text.is_empty() {
+        info!("Nothing to add");
+        return -1;
+    }
+
+    let res = if prepend {
+        util::prepend(&conn, id, &text)
+    } else {
+        util::append(&conn, id, &text)
+    };
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        return -2;
+    }
+
+    0
+}
+
+pub fn config(args: &clap::ArgMatches) -> i32 {
+    let path = Config::config_path();
+    if let ("path", Some(_)) = args.subcommand() {
+        println!("{}", path.display());
+        return 0;
+    }
+
+    let config = match Config::load_default() {
+        Ok(config) => config,
+        Err(err) => {
+            super::print_config_error(&err);
+            return 1;
+        }
+    };
+
+    if path.is_file() {
+        println!("Config file: {}", path.display());
+    } else {
+        println!("Config file: {} (not found, using the defaults)", path.display());
+    }
+
+    println!("Storages:");
+    storages(&config);
+
+    println!("Programs:");
+    let programs = [
+        ("editor", config.editor()),
+        ("pager", config.pager()),
+        ("clipboard", config.clipboard()),
+        ("markdown", config.markdown_renderer()),
+    ];
+    for (name, prog) in programs.iter() {
+        if let Some(prog) = prog {
+            println!("  {}\t{}", name, prog.join(" "));
+        }
+    }
+
+    if programs.iter().all(|(_, prog)| prog.is_none()) {
+        println!("  none configured");
+    }
+
+    0
+}
+
+pub fn storages(config: &Config) -> i32 {
+    let mut storages: Vec<_> = config.storages().collect();
+    storages.sort();
+    for (name, path) in storages {
+        let mark = if name == config.default_storage() { "*" } else { " " };
+        let note = if path.join("nodes.db").is_file() {
+            ""
+        } else {
+            " (no database yet)"
+        };
+        println!("{} {}\t{}{}", mark, name, path.display(), note);
+    }
+
+    0
+}
+
 pub fn add_tag(conn: &Connection, args: &clap::ArgMatches) -> i32 {
     let tags: Vec<&str> = args.values_of("tag").unwrap().collect();
     let nodes = util::gather_nodes(&args, "id");
     if nodes.is_empty() {
-        println!("No valid ids given");
+        info!("No valid ids given");
         return -1;
     }
 
@@ -120,7 +561,7 @@ pub fn remove_tag(conn: &Connection, args: &clap::ArgMatches) -> i32 {
     let tags: Vec<&str> = args.values_of("tag").unwrap().collect();
     let nodes = util::gather_nodes(&args, "id");
     if nodes.is_empty() {
-        println!("No valid ids given");
+        info!("No valid ids given");
         return -1;
     }
 
@@ -136,7 +577,7 @@ pub fn remove_tag(conn: &Connection, args: &clap::ArgMatches) -> i32 {
 pub fn archive(conn: &Connection, args: &clap::ArgMatches) -> i32 {
     let nodes = util::gather_nodes(&args, "id");
     if nodes.is_empty() {
-        println!("No valid ids given");
+        info!("No valid ids given");
         return -1;
     }
 
@@ -148,3 +589,609 @@ pub fn archive(conn: &Connection, args: &clap::ArgMatches) -> i32 {
         }
     }
 }
+
+pub fn priority(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    let value = value_t!(args, "value", i32).unwrap_or_else(|e| e.exit());
+    let nodes = util::gather_nodes(&args, "id");
+    if nodes.is_empty() {
+        info!("No valid ids given");
+        return -1;
+    }
+
+    match util::priority_set(&conn, &nodes, value) {
+        Ok(_) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+fn parse_exec_ids(args: &[&str]) -> Result<Vec<u32>, String> {
+    if args.is_empty() {
+        return Err("No node ids given".to_string());
+    }
+
+    args.iter().map(|a| a.parse::<u32>()
+            .map_err(|_| format!("Invalid node '{}'", a)))
+        .collect()
+}
+
+// Executes a single line of the exec mini-language.
+fn exec_line(conn: &Connection, line: &str) -> Result<(), String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args[0] {
+        "tag" | "untag" if args.len() > 2 => {
+            let ids = parse_exec_ids(&args[1..2])?;
+            let res = if args[0] == "tag" {
+                util::add_tags(&conn, &ids, &args[2..])
+            } else {
+                util::remove_tags(&conn, &ids, &args[2..])
+            };
+            res.map_err(|e| e.to_string())
+        }, "archive" => {
+            let ids = parse_exec_ids(&args[1..])?;
+            util::toggle_archived_range(&conn, &ids).map_err(|e| e.to_string())
+        }, "rm" => {
+            let ids = parse_exec_ids(&args[1..])?;
+            util::delete_range(&conn, &ids).map(|_| ()).map_err(|e| e.to_string())
+        }, "priority" if args.len() == 3 => {
+            let ids = parse_exec_ids(&args[1..2])?;
+            let offset = args[2].trim_start_matches('+').parse::<i32>()
+                .map_err(|_| format!("Invalid priority offset '{}'", args[2]))?;
+            util::priority_add(&conn, &ids, offset).map_err(|e| e.to_string())
+        }, _ => Err(format!("Invalid command '{}'", line)),
+    }
+}
+
+pub fn exec(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    let file = args.value_of("file").unwrap();
+    let input: Box<dyn BufRead> = if file == "-" {
+        Box::new(io::BufReader::new(io::stdin()))
+    } else {
+        match File::open(file) {
+            Ok(f) => Box::new(io::BufReader::new(f)),
+            Err(err) => {
+                eprintln!("Failed to open '{}': {}", file, err);
+                return -1;
+            }
+        }
+    };
+
+    // all lines are applied in a single transaction. We continue
+    // after errors to report all of them but won't apply anything then
+    let mut failed = 0;
+    let mut succeeded = Vec::new(); // reported once they are committed
+    let res = util::transaction(&conn, || {
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match exec_line(&conn, line) {
+                Ok(_) => succeeded.push(i + 1),
+                Err(err) => {
+                    eprintln!("line {}: {}", i + 1, err);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            Err(util::Error::Aborted)
+        } else {
+            Ok(())
+        }
+    });
+
+    match res {
+        Ok(_) => {
+            for line in succeeded {
+                println!("line {}: ok", line);
+            }
+            0
+        }, Err(util::Error::Aborted) => {
+            eprintln!("{} invalid commands, no changes applied", failed);
+            failed
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+// Returns the key used to detect duplicate contents: the content
+// itself or its words separated by single spaces.
+fn content_key(content: &str, ignore_whitespace: bool) -> String {
+    if ignore_whitespace {
+        content.split_whitespace().collect::<Vec<_>>().join(" ")
+    } else {
+        content.to_string()
+    }
+}
+
+// Asks the user which node of a duplicate group to keep, merges the
+// tags of the others into it and removes them.
+fn merge_dups(conn: &Connection, group: &[(u32, String, Vec<String>)])
+        -> Result<(), util::Error> {
+    for (id, summary, _) in group {
+        println!("{}:\t{}", id, summary);
+    }
+
+    let survivor = loop {
+        print!("Keep which node? [id, empty to skip] ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        match line.parse::<u32>() {
+            Ok(id) if group.iter().any(|n| n.0 == id) => break id,
+            _ => println!("'{}' is not part of this group", line),
+        }
+    };
+
+    util::transaction(conn, || {
+        let others: Vec<u32> = group.iter()
+            .map(|n| n.0)
+            .filter(|id| *id != survivor)
+            .collect();
+        let mut tags: Vec<&str> = group.iter()
+            .flat_map(|n| n.2.iter().map(|t| t.as_str()))
+            .collect();
+        tags.sort();
+        tags.dedup();
+
+        // remove them first so that re-adding tags the survivor
+        // already has doesn't violate the primary key
+        if !tags.is_empty() {
+            util::remove_tags(conn, &[survivor], &tags)?;
+            util::add_tags(conn, &[survivor], &tags)?;
+        }
+        util::delete_range(conn, &others)?;
+        Ok(())
+    })
+}
+
+pub fn dups(conn: &Connection, config: &Config, args: &clap::ArgMatches) -> i32 {
+    let ignore_whitespace = args.is_present("ignore_whitespace");
+    let strip = config.strip_heading_markers();
+    let list_args = util::QueryArgs {
+        preorder: util::Order::Asc,
+        postorder: util::Order::Asc,
+        count: None,
+        pattern: None,
+        archived: util::ArchivedFilter::All,
+        sort: Some(util::Sort::ID),
+        ids: None,
+        content_prefix: None,
+        pinned_first: false,
+    };
+
+    // groups are kept in order of their first node
+    let mut groups: Vec<Vec<(u32, String, Vec<String>)>> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let res = util::iter_nodes(&conn, &list_args, |node| {
+        let key = content_key(node.content, ignore_whitespace);
+        let i = *index.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+
+        let summary = util::node_summary(node.content, 1, 60, strip);
+        let tags = node.tags.iter().map(|t| t.to_string()).collect();
+        groups[i].push((node.id, summary, tags));
+    });
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        return -2;
+    }
+
+    for group in &groups {
+        if group.len() < 2 {
+            continue;
+        }
+
+        if args.is_present("merge") {
+            if let Err(err) = merge_dups(&conn, group) {
+                eprintln!("{}", err);
+                return -2;
+            }
+            println!();
+        } else {
+            let ids: Vec<String> = group.iter().map(|n| n.0.to_string()).collect();
+            println!("{}", ids.join(" "));
+        }
+    }
+
+    0
+}
+
+pub fn tags(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    match args.subcommand() {
+        ("merge", Some(s)) => return tags_merge(conn, s),
+        ("rename", Some(s)) => return tags_rename(conn, s),
+        ("prune", Some(s)) => return tags_prune(conn, s),
+        _ => (),
+    }
+
+    let tags = match args.value_of("depth") {
+        Some(depth) => util::list_tag_prefixes(&conn, depth.parse().unwrap()),
+        None => util::list_tags(&conn),
+    };
+
+    match tags {
+        Ok(tags) => {
+            for (tag, count) in tags {
+                println!("{}\t{}", tag, count);
+            }
+            0
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+pub fn export(conn: &Connection, config: &Config, storage: &str,
+        args: &clap::ArgMatches) -> i32 {
+    // always export everything matching, in a stable order
+    let mut list_args = util::extract_list_args(&args, config, storage,
+        false, false);
+    list_args.count = None;
+    list_args.sort = Some(util::Sort::ID);
+    list_args.preorder = util::Order::Asc;
+    list_args.postorder = util::Order::Asc;
+    list_args.pinned_first = false;
+    list_args.content_prefix = Some(0);
+
+    let ids = match util::query_nodes(&conn, &list_args) {
+        Ok(nodes) => nodes.iter().map(|node| node.id).collect::<Vec<_>>(),
+        Err(err) => {
+            eprintln!("{}", err);
+            return -2;
+        }
+    };
+
+    let format = match args.value_of("format") {
+        _ if args.is_present("csv") => "csv",
+        Some(format) => format,
+        None => "json",
+    };
+    if format == "ndjson" {
+        return export_ndjson(conn, &ids);
+    }
+
+    let nodes = match util::backup_nodes(&conn, &ids) {
+        Ok(nodes) => nodes,
+        Err(err) => {
+            eprintln!("{}", err);
+            return -2;
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let res = if format == "csv" {
+        util::write_csv(&mut stdout, &nodes).map_err(io::Error::from)
+    } else {
+        serde_json::to_writer_pretty(&mut stdout, &nodes)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(stdout))
+    };
+    if let Err(err) = res {
+        eprintln!("Failed to write nodes: {}", err);
+        return -2;
+    }
+
+    info!("Exported {} nodes", nodes.len());
+    0
+}
+
+// Writes one node per line. The nodes are fetched and written in
+// chunks, so the whole storage is never held in memory.
+fn export_ndjson(conn: &Connection, ids: &[u32]) -> i32 {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut count = 0;
+    for chunk in ids.chunks(100) {
+        let nodes = match util::backup_nodes(conn, chunk) {
+            Ok(nodes) => nodes,
+            Err(err) => {
+                eprintln!("{}", err);
+                return -2;
+            }
+        };
+
+        let res = nodes.iter().try_for_each(|node| {
+            serde_json::to_writer(&mut out, node).map_err(io::Error::from)?;
+            writeln!(out)
+        }).and_then(|_| out.flush());
+        if let Err(err) = res {
+            eprintln!("Failed to write nodes: {}", err);
+            return -2;
+        }
+
+        count += nodes.len();
+    }
+
+    info!("Exported {} nodes", count);
+    0
+}
+
+pub fn import(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    let file = args.value_of("file").unwrap_or("-");
+    let stdin = io::stdin();
+    let mut input: Box<dyn BufRead> = if file == "-" {
+        Box::new(stdin.lock())
+    } else {
+        match File::open(file) {
+            Ok(f) => Box::new(io::BufReader::new(f)),
+            Err(err) => {
+                eprintln!("Failed to open '{}': {}", file, err);
+                return -1;
+            }
+        }
+    };
+
+    // export writes either a single array or one node per line
+    let array = match starts_with_array(&mut input) {
+        Ok(array) => array,
+        Err(err) => {
+            eprintln!("Failed to read '{}': {}", file, err);
+            return -1;
+        }
+    };
+
+    let nodes: serde_json::Result<Vec<util::ExportNode>> = if array {
+        serde_json::from_reader(input)
+    } else {
+        serde_json::Deserializer::from_reader(input).into_iter().collect()
+    };
+    let nodes = match nodes {
+        Ok(nodes) => nodes,
+        Err(err) => {
+            eprintln!("Invalid nodes: {}", err);
+            return -1;
+        }
+    };
+
+    match util::import_nodes(&conn, &nodes, args.is_present("preserve_ids")) {
+        Ok(_) => {
+            info!("Imported {} nodes", nodes.len());
+            0
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+// Skips leading whitespace and returns whether the input continues
+// with a '['.
+fn starts_with_array(input: &mut dyn BufRead) -> io::Result<bool> {
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => {
+                let array = buf[i] == b'[';
+                input.consume(i);
+                return Ok(array);
+            }, None => {
+                let len = buf.len();
+                input.consume(len);
+            }
+        }
+    }
+}
+
+pub fn stats(conn: &Connection) -> i32 {
+    let stats = match util::collect_stats(&conn) {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!("{}", err);
+            return -2;
+        }
+    };
+
+    println!("nodes\t{}", stats.nodes);
+    println!("archived\t{}", stats.archived);
+    println!("tags\t{}", stats.tags);
+    println!("priority\tavg {:.2}, median {}", stats.avg_priority,
+        stats.median_priority);
+    if let (Some(oldest), Some(newest)) = (stats.oldest_edit, stats.newest_edit) {
+        println!("edited\t{} to {}", oldest, newest);
+    }
+    println!("words\t{}", stats.words);
+    println!("chars\t{}", stats.chars);
+
+    0
+}
+
+/// Moves nodes from the storage of conn (named storage) into another
+/// storage. They get new ids there, which are printed.
+pub fn move_nodes(conn: &Connection, config: &Config, storage: &str,
+        args: &clap::ArgMatches) -> i32 {
+    let to = args.value_of("to").unwrap();
+    let mut path = match config.storage_folder(to) {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("Storage '{}' unknown", to);
+            return -1;
+        }
+    };
+
+    if Some(&path) == config.storage_folder(storage) {
+        eprintln!("Nodes are already in storage '{}'", to);
+        return -1;
+    }
+
+    path.push("nodes.db");
+    verbose!("Moving to storage '{}' at {}", to, path.display());
+    let target = match util::open_db(&path) {
+        Ok(target) => target,
+        Err(err) => {
+            eprintln!("Failed to open storage '{}': {}", to, err);
+            return -2;
+        }
+    };
+
+    if let Err(err) = super::init_database(&target) {
+        eprintln!("Failed to initialize storage '{}': {}", to, err);
+        return -2;
+    }
+
+    // ids given twice are only moved (and counted) once
+    let mut ids = util::gather_nodes(&args, "id");
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+    let nodes = match util::backup_nodes(&conn, &ids) {
+        Ok(nodes) => nodes,
+        Err(err) => {
+            eprintln!("{}", err);
+            return -2;
+        }
+    };
+
+    for id in &ids {
+        if !nodes.iter().any(|node| node.id == *id) {
+            eprintln!("Invalid node id {}", id);
+        }
+    }
+
+    // the deletion is rolled back if the copies can't be committed.
+    // Worst case committing it fails afterwards and the nodes end up
+    // in both storages
+    let res = util::transaction(&conn, || {
+        let old: Vec<u32> = nodes.iter().map(|node| node.id).collect();
+        util::delete_range(&conn, &old)?;
+        util::import_nodes(&target, &nodes, false)
+    });
+
+    match res {
+        Ok(new) => {
+            for id in &new {
+                println!("{}", id);
+            }
+            info!("Moved {} nodes to '{}'", new.len(), to);
+            (ids.len() - new.len()) as i32
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+fn tags_merge(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    let from: Vec<&str> = args.values_of("from").unwrap().collect();
+    let into = args.value_of("into").unwrap();
+    match util::merge_tags(&conn, &from, into) {
+        Ok(count) => {
+            info!("Merged tags of {} nodes", count);
+            0
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+fn tags_rename(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    let from = args.value_of("from").unwrap();
+    let to = args.value_of("to").unwrap();
+    match util::rename_tag(&conn, from, to) {
+        Ok(count) => {
+            info!("Renamed the tag of {} nodes", count);
+            0
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+fn tags_prune(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+    let min_count = match args.value_of("min_count") {
+        Some(_) => Some(value_t!(args, "min_count", u32)
+            .unwrap_or_else(|e| e.exit())),
+        None => None,
+    };
+
+    match util::prune_tags(&conn, min_count) {
+        Ok(count) => {
+            info!("Removed {} tags", count);
+            0
+        }, Err(err) => {
+            eprintln!("{}", err);
+            -2
+        }
+    }
+}
+
+// Returns the ids of the active nodes, least recently viewed first.
+fn least_viewed(conn: &Connection, count: Option<usize>)
+        -> Result<Vec<u32>, util::Error> {
+    let query = "
+        SELECT id
+        FROM nodes
+        WHERE archived = 0
+        ORDER BY viewed ASC, id ASC
+        LIMIT ?1";
+    // a negative limit means no limit in sqlite
+    let limit = count.map(|c| c as i64).unwrap_or(-1);
+    let mut stmt = conn.prepare(query)?;
+    let ids = stmt.query_map(&[&limit], |row| row.get(0))?
+        .collect::<Result<Vec<u32>, _>>()?;
+    Ok(ids)
+}
+
+pub fn review(conn: &Connection, options: &util::EditOptions,
+        args: &clap::ArgMatches) -> i32 {
+    let count = args.value_of("count").map(|c| c.parse::<usize>().unwrap());
+    let ids = match least_viewed(&conn, count) {
+        Ok(ids) => ids,
+        Err(err) => {
+            eprintln!("{}", err);
+            return -2;
+        }
+    };
+
+    for (i, id) in ids.iter().enumerate() {
+        // editing also updates the viewed time
+        if let Err(err) = util::edit(&conn, options, *id, None) {
+            eprintln!("{}", err);
+            return -6;
+        }
+
+        if i + 1 == ids.len() {
+            break;
+        }
+
+        print!("Reviewed node {} ({}/{}). Next? [Y/n] ", id, i + 1, ids.len());
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+
+        match line.trim() {
+            "n" | "N" | "q" => break,
+            _ => (),
+        }
+    }
+
+    0
+}
@@ -0,0 +1,259 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use termion::event::Key;
+
+// maximum number of history entries kept
+const HISTORY_SIZE: usize = 500;
+
+/// Single line text input with a cursor.
+/// Used for the search and command line of the select screen.
+pub struct LineInput {
+    buffer: String,
+    cursor: usize, // byte offset into buffer, always at a char boundary
+}
+
+impl LineInput {
+    pub fn new() -> LineInput {
+        LineInput {
+            buffer: String::new(),
+            cursor: 0,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.buffer
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.cursor = 0;
+    }
+
+    /// Replaces the text, the cursor is moved to the end.
+    pub fn set(&mut self, text: &str) {
+        self.buffer = text.to_string();
+        self.cursor = self.buffer.len();
+    }
+
+    /// Returns the current text and clears the input.
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::replace(&mut self.buffer, String::new())
+    }
+
+    // offset of the char before the cursor
+    fn prev_boundary(&self) -> usize {
+        self.buffer[..self.cursor].char_indices()
+            .next_back()
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    // offset of the char after the cursor
+    fn next_boundary(&self) -> usize {
+        self.buffer[self.cursor..].chars()
+            .next()
+            .map(|c| self.cursor + c.len_utf8())
+            .unwrap_or(self.cursor)
+    }
+
+    /// Handles an editing key.
+    /// Returns false if the key isn't an editing key.
+    pub fn input(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char('\n') => return false,
+            Key::Char(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            },
+            Key::Backspace => {
+                let prev = self.prev_boundary();
+                self.buffer.replace_range(prev..self.cursor, "");
+                self.cursor = prev;
+            },
+            Key::Delete => {
+                let next = self.next_boundary();
+                self.buffer.replace_range(self.cursor..next, "");
+            },
+            Key::Left => self.cursor = self.prev_boundary(),
+            Key::Right => self.cursor = self.next_boundary(),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.buffer.len(),
+            Key::Ctrl('w') => { // delete word before cursor
+                let before = &self.buffer[..self.cursor];
+                let start = before.trim_end().char_indices().rev()
+                    .find(|(_, c)| c.is_whitespace())
+                    .map(|(i, c)| i + c.len_utf8())
+                    .unwrap_or(0);
+                self.buffer.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            },
+            Key::Ctrl('u') => self.clear(),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Renders the line with the given prefix at the given row.
+    /// The cursor is drawn as inverted char.
+    pub fn render<W: Write>(&self, w: &mut W, prefix: &str, y: u16) {
+        let next = self.next_boundary();
+        let under = if next == self.cursor {
+            " "
+        } else {
+            &self.buffer[self.cursor..next]
+        };
+
+        write!(w, "{}{}{}{}{}{}{}{}{}",
+            termion::cursor::Goto(1, y),
+            termion::clear::CurrentLine,
+            termion::color::Fg(termion::color::Reset),
+            termion::color::Bg(termion::color::Reset),
+            prefix,
+            &self.buffer[..self.cursor],
+            termion::style::Invert,
+            under,
+            termion::style::Reset).unwrap();
+        write!(w, "{}", &self.buffer[next..]).unwrap();
+    }
+}
+
+/// History of entered lines, browsable with up/down.
+/// When a path is given, the history is loaded from and appended to
+/// that file. Once it is full, the file is rewritten with the kept
+/// entries so it doesn't grow beyond HISTORY_SIZE lines.
+pub struct History {
+    entries: Vec<String>,
+    pos: Option<usize>, // currently shown entry while browsing
+    draft: String, // the line as it was before browsing started
+    path: Option<PathBuf>,
+}
+
+impl History {
+    pub fn load(path: Option<PathBuf>) -> History {
+        let mut entries: Vec<String> = path.as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|s| s.lines().map(|l| l.to_string()).collect())
+            .unwrap_or_default();
+        let skip = entries.len().saturating_sub(HISTORY_SIZE);
+        entries.drain(..skip);
+
+        History {
+            entries,
+            pos: None,
+            draft: String::new(),
+            path,
+        }
+    }
+
+    /// Adds the given entry and stops browsing.
+    /// Empty entries and repetitions of the last entry are ignored.
+    pub fn push(&mut self, entry: &str) {
+        self.pos = None;
+        if entry.is_empty() || self.entries.last().map(|e| e.as_str()) == Some(entry) {
+            return;
+        }
+
+        self.entries.push(entry.to_string());
+        let full = self.entries.len() > HISTORY_SIZE;
+        if full {
+            self.entries.remove(0);
+        }
+
+        if let Some(path) = &self.path {
+            // the history is just a convenience, ignore failures
+            let _ = if full {
+                History::write(path, &self.entries)
+            } else {
+                History::append(path, entry)
+            };
+        }
+    }
+
+    fn append(path: &PathBuf, entry: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", entry)
+    }
+
+    fn write(path: &PathBuf, entries: &[String]) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        for entry in entries {
+            writeln!(file, "{}", entry)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the previous entry. The current line is needed to
+    /// restore it when browsing back down.
+    pub fn prev(&mut self, current: &str) -> Option<&str> {
+        let pos = match self.pos {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }, Some(0) => 0,
+            Some(pos) => pos - 1,
+        };
+
+        self.pos = Some(pos);
+        Some(&self.entries[pos])
+    }
+
+    /// Returns the next entry or the line from before browsing
+    /// if the end of the history was reached.
+    pub fn next(&mut self) -> Option<&str> {
+        let pos = self.pos?;
+        if pos + 1 < self.entries.len() {
+            self.pos = Some(pos + 1);
+            Some(&self.entries[pos + 1])
+        } else {
+            self.pos = None;
+            Some(&self.draft)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delete_word() {
+        let mut input = LineInput::new();
+        input.set("tag a\u{3000}b c ");
+        input.input(Key::Ctrl('w'));
+        assert_eq!(input.text(), "tag a\u{3000}b ");
+        input.input(Key::Ctrl('w'));
+        assert_eq!(input.text(), "tag a\u{3000}");
+        input.input(Key::Ctrl('w'));
+        assert_eq!(input.text(), "tag ");
+        input.input(Key::Ctrl('w'));
+        assert_eq!(input.text(), "");
+    }
+
+    #[test]
+    fn history_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::load(Some(path.clone()));
+        for i in 0..HISTORY_SIZE + 10 {
+            history.push(&i.to_string());
+        }
+
+        let lines: Vec<String> = fs::read_to_string(&path).unwrap()
+            .lines().map(|l| l.to_string()).collect();
+        assert_eq!(lines.len(), HISTORY_SIZE);
+        assert_eq!(lines[0], "10");
+        assert_eq!(lines.last().unwrap(), &(HISTORY_SIZE + 9).to_string());
+
+        let mut loaded = History::load(Some(path));
+        assert_eq!(loaded.prev(""), Some(lines.last().unwrap().as_str()));
+    }
+}
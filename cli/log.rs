@@ -0,0 +1,38 @@
+// Minimal logging for the command line interface.
+// Informational messages go to stderr and can be disabled via --quiet,
+// verbose messages (resolved paths, generated sql, ...) are only
+// printed with --verbose.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const QUIET: usize = 0;
+pub const NORMAL: usize = 1;
+pub const VERBOSE: usize = 2;
+
+static LEVEL: AtomicUsize = AtomicUsize::new(NORMAL);
+
+pub fn set_level(level: usize) {
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: usize) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level
+}
+
+/// Prints an informational message, unless --quiet was given.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::NORMAL) {
+            eprintln!($($arg)*);
+        }
+    }
+}
+
+/// Prints a debug message if --verbose was given.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::VERBOSE) {
+            eprintln!($($arg)*);
+        }
+    }
+}
@@ -1,10 +1,16 @@
 use rusqlite::Connection;
 use clap::clap_app;
-use nodes::Config;
+use nodes::{Config, ConfigError};
 
+#[macro_use]
+mod log;
 mod commands;
 mod util;
 mod select;
+mod input;
+
+const ARCHIVED_VALUES: &[&str] = &["active", "archived", "all",
+    "no", "yes", "both"];
 
 fn is_uint(v: String) -> Result<(), String> {
     if let Err(_) = v.parse::<u64>() {
@@ -14,6 +20,14 @@ fn is_uint(v: String) -> Result<(), String> {
     }
 }
 
+fn is_priority(v: String) -> Result<(), String> {
+    match v.parse::<i32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Could not parse '{}' as priority, \
+            expected a 32-bit signed integer", v)),
+    }
+}
+
 fn is_node(v: String) -> Result<(), String> {
     // TODO: re-add handling of those
     // would require new table though probably
@@ -26,6 +40,46 @@ fn is_node(v: String) -> Result<(), String> {
     is_uint(v)
 }
 
+fn is_line_range(v: String) -> Result<(), String> {
+    commands::parse_line_range(&v).map(|_| ())
+}
+
+// Prints a human readable description of the given config error.
+// Used instead of a panic since hand-editing the config makes
+// this the error new users run into first.
+fn print_config_error(err: &ConfigError) {
+    let path = Config::config_path();
+    match err {
+        // file:line:col can be opened directly by most editors
+        ConfigError::Parse(e) if e.line_col().is_some() => {
+            let (line, col) = e.line_col().unwrap();
+            eprintln!("Invalid config file {}:{}:{}", path.display(),
+                line + 1, col + 1);
+            eprintln!("{}", err);
+        }, err => eprintln!("Invalid config file {}: {}", path.display(), err),
+    }
+}
+
+// Creates or upgrades the database schema as needed.
+fn init_database(conn: &Connection) -> Result<(), String> {
+    use nodes::db;
+    let err = |e: rusqlite::Error| e.to_string();
+    db::init_schema(conn).map_err(err)?;
+    let version = db::schema_version(conn).map_err(err)?;
+    verbose!("Schema version: {:?}", version);
+    match version {
+        Some(v) if v > db::CURRENT_SCHEMA_VERSION => Err(format!(
+            "The storage uses schema version {}, only {} is supported. \
+            Is this an old version of nodes?", v, db::CURRENT_SCHEMA_VERSION)),
+        Some(v) if v == db::CURRENT_SCHEMA_VERSION => Ok(()),
+        _ => {
+            info!("Upgrading the storage to schema version {}",
+                db::CURRENT_SCHEMA_VERSION);
+            db::migrate(conn).map_err(err)
+        }
+    }
+}
+
 fn main() -> rusqlite::Result<()> {
     // TODO:
     // - archived
@@ -34,16 +88,42 @@ fn main() -> rusqlite::Result<()> {
         (setting: clap::AppSettings::VersionlessSubcommands)
         (author: "nyorain [at gmail dot com]")
         (about: "Manages your node system from the command line")
+        (after_help: "The sqlite synchronous mode can be set via \
+            `synchronous = off|normal|full|extra` in the [storage] table \
+            of the config. The default, off, is much faster on slow disks \
+            but recent changes may be lost or the database corrupted on \
+            a power loss or system crash. Therefore no storage can be \
+            named 'synchronous'.")
         (@arg storage: -s --storage +takes_value "The storage to use")
+        (@arg memory: --memory conflicts_with[storage]
+            "Use a temporary in-memory storage instead, \
+            nothing is written to disk")
+        (@arg verbose: -v --verbose
+            "Print the resolved storage, generated sql queries and \
+            editor commands to stderr")
+        (@arg quiet: -q --quiet conflicts_with[verbose]
+            "Don't print informational messages")
+        (@arg null: --null short("z") +global
+            "Node ids read from stdin are separated by NUL bytes \
+            instead of newlines, e.g. for find -print0 style output")
+        (@arg editor: --editor +takes_value +global
+            "The editor command to use, split at whitespace. Takes \
+            precedence over the config and $VISUAL/$EDITOR")
         (@subcommand create =>
             (about: "Creates a new node")
             (alias: "c")
             (@arg tags: -t --tag +takes_value !required ... +use_delimiter
                 "Tag the node")
             (@arg content: -c --content +takes_value !required
-                "Write this content into the node instead of open an editor")
+                "Write this content into the node instead of open an editor. \
+                Without it, piped stdin is used as content")
         ) (@subcommand rm =>
             (about: "Removes a node (by id)")
+            (@arg dry_run: -n --("dry-run")
+                "Only print the nodes that would be removed")
+            (@arg yes: -y --yes
+                "Don't ask for confirmation. Required when stdin \
+                isn't a terminal, e.g. when the ids are piped")
             (@arg id: +multiple index(1) {is_node}
                 "The node ids. Can also specify multiple nodes. \
                 If not given, will read from stdin")
@@ -56,14 +136,40 @@ fn main() -> rusqlite::Result<()> {
                 default_value("999999")
                 {is_uint}
                 "Maximum number of nodes to show")
-            (@arg archived: -a !takes_value !required
-                "Show only archived nodes")
+            (@arg archived: --archived +takes_value !required
+                possible_values(ARCHIVED_VALUES)
+                "Which nodes to show: no (default) | yes | both. \
+                active, archived and all are accepted as well")
+            (@arg all: -a !takes_value !required
+                conflicts_with[archived only_archived]
+                "Include archived nodes, same as --archived both")
             (@arg only_archived: -A !takes_value !required
-                "Only show archived nodes")
+                conflicts_with[archived]
+                "Only show archived nodes, same as --archived yes")
             (@arg reverse: -r --rev !takes_value !required
                 "Reverses the node/display order. Default is ascending")
             (@arg sort: -s --sort +takes_value !required
-                "How to initially sort the nodes: id | priority | edited")
+                "How to initially sort the nodes: \
+                id | priority | created | edited | viewed | random. \
+                Random ignores --rev")
+            (@arg no_pin_priority: --("no-pin-priority")
+                "Sort pinned nodes like all others instead of \
+                showing them first")
+            (@arg stdin: --stdin
+                "Only show the nodes whose ids are read from stdin. \
+                Keys are always read from the terminal")
+            (@arg print: --print +takes_value default_value("id")
+                possible_values(&["id", "summary", "content"])
+                "What to print for each selected node: the id, \
+                the first line or the whole content")
+            (@arg delimiter: -d --delimiter +takes_value default_value("---")
+                "Printed on its own line between the nodes with --print content")
+            (@arg print0: --print0 short("0")
+                "Terminate each printed node with NUL instead of a newline, \
+                e.g. for xargs -0. Replaces the delimiter")
+            (@arg tag: --tag +takes_value ... number_of_values(1)
+                "Only show nodes with this tag. Can be given multiple times, \
+                nodes must have all of them. Combined with the pattern")
         ) (@subcommand ls =>
             (about: "Lists existing notes")
             (@arg pattern: index(1)
@@ -76,24 +182,103 @@ fn main() -> rusqlite::Result<()> {
                 {is_uint}
                 "How many lines to show at maximum from a node")
             (@arg full: -f --full conflicts_with("lines") "Print full nodes")
+            (@arg width: -w --width +takes_value {is_uint}
+                "Maximum width of the summary lines. Defaults to $COLUMNS, \
+                the terminal width or 80 when not writing to a terminal")
             (@arg reverse: -R --rev !takes_value !required
                 "Reverses the node order (before counting). Default is descending")
             (@arg reverse_display: -r --revdisplay !takes_value !required
                 "Reverses the display order. Default is ascending")
-            (@arg archived: -a !takes_value !required
-                "Include archived nodes")
+            (@arg archived: --archived +takes_value !required
+                possible_values(ARCHIVED_VALUES)
+                "Which nodes to show: no (default) | yes | both. \
+                active, archived and all are accepted as well")
+            (@arg all: -a !takes_value !required
+                conflicts_with[archived only_archived]
+                "Include archived nodes, same as --archived both")
             (@arg only_archived: -A !takes_value !required
-                "Only show archived nodes")
+                conflicts_with[archived]
+                "Only show archived nodes, same as --archived yes")
             (@arg sort: -s --sort +takes_value !required
-                "How to sort the nodes: id | priority | edited")
+                "How to sort the nodes: \
+                id | priority | created | edited | viewed | random. \
+                Random ignores --rev and --revdisplay")
+            (@arg no_pin_priority: --("no-pin-priority")
+                "Sort pinned nodes like all others instead of \
+                showing them first")
+            (@arg oldest: --oldest +takes_value {is_uint}
+                conflicts_with[newest num sort reverse]
+                "Show the given number of oldest nodes, see --by")
+            (@arg newest: --newest +takes_value {is_uint}
+                conflicts_with[num sort reverse]
+                "Show the given number of newest nodes, see --by")
+            (@arg by: --by +takes_value possible_values(&["edited", "viewed", "created", "id"])
+                "Which field --oldest and --newest use: \
+                edited (default) | viewed | created | id")
+            (@arg group_by: --("group-by") +takes_value possible_values(&["tag"])
+                "Print the nodes grouped under each of their tags. \
+                A node appears once for every tag it has")
+            (@arg untagged: --untagged requires[group_by]
+                "Also print a group with the nodes without tags")
+            (@arg tag: --tag +takes_value ... number_of_values(1)
+                "Only show nodes with this tag. Can be given multiple times, \
+                nodes must have all of them. Combined with the pattern")
+            (@arg count: --count conflicts_with[format group_by]
+                "Show the number of words and chars of each node \
+                before its summary")
+            (@arg total: --total conflicts_with[group_by]
+                "Print how many nodes matched in total when --num cut \
+                off the list. Done by default when writing to a terminal")
+            (@arg format: --format +takes_value
+                conflicts_with[group_by lines full width]
+                "Print each node as described by the template. \
+                Placeholders: %i (id), %p (priority), %s (first line), \
+                %t (comma-separated tags), %e (edited), %% (literal %)")
+            (@arg print0: --print0 short("0") conflicts_with[group_by count lines full total]
+                "Terminate each node with NUL instead of a newline, e.g. \
+                for xargs -0. Only prints the ids if no --format is given")
+            (@arg no_pager: --("no-pager")
+                "Don't pipe the output through the pager (programs.pager, \
+                $PAGER or less). It's only used when writing to a terminal")
         ) (@subcommand output =>
-            (about: "Output the content of a node")
+            (about: "Output the content of nodes")
             (alias: "o")
-            (@arg id: +required index(1) {is_node} "Id of node to show")
+            (@arg id: +multiple index(1) {is_node}
+                "The node ids. Can also specify multiple nodes. \
+                If not given, will read from stdin")
+            (@arg no_pager: --("no-pager")
+                "Don't pipe the output through the pager (programs.pager, \
+                $PAGER or less). It's only used when writing to a terminal")
+            (@arg delimiter: -d --delimiter +takes_value default_value("---")
+                "Printed on its own line between the nodes. \
+                With --raw it is written as is")
+            (@arg render: --render
+                "Render the content with the configured markdown renderer \
+                (programs.markdown). Outputs raw content if none is set")
+            (@arg meta: -m --meta
+                "Print the id, priority, tags and dates before the content")
+            (@arg raw: --raw conflicts_with[render]
+                "Write the content exactly as stored, without trailing newline")
+            (@arg no_touch: --("no-touch")
+                "Don't update the time the node was last viewed")
         ) (@subcommand edit =>
             (about: "Edits a node")
             (alias: "e")
             (@arg id: --id +required index(1) {is_node} "Id of node to edit")
+            (@arg lines: -l --lines +takes_value {is_line_range}
+                "Only edit the given lines (1-based, inclusive), e.g. 10:20")
+        ) (@subcommand append =>
+            (about: "Appends text to a node on a new line, \
+                without opening an editor")
+            (@arg id: +required index(1) {is_node} "Id of the node")
+            (@arg content: -c --content +takes_value +allow_hyphen_values
+                "The text to append. Read from stdin if not given")
+        ) (@subcommand prepend =>
+            (about: "Inserts text at the start of a node on its own line, \
+                without opening an editor")
+            (@arg id: +required index(1) {is_node} "Id of the node")
+            (@arg content: -c --content +takes_value +allow_hyphen_values
+                "The text to prepend. Read from stdin if not given")
         ) (@subcommand addtag =>
             (about: "Adds a tag to a node")
             (alias: "at")
@@ -101,6 +286,14 @@ fn main() -> rusqlite::Result<()> {
             (@arg id: +multiple index(2) {is_node}
                 "The node ids. Can also specify multiple nodes. \
                 If not given, will read from stdin")
+        ) (@subcommand priority =>
+            (about: "Sets the priority of nodes")
+            (setting: clap::AppSettings::AllowNegativeNumbers)
+            (@arg value: +required index(1) {is_priority}
+                "The new priority, may be negative")
+            (@arg id: +multiple index(2) {is_node}
+                "The node ids. Can also specify multiple nodes. \
+                If not given, will read from stdin")
         ) (@subcommand rmtag =>
             (about: "Adds a tag to a node")
             (alias: "rt")
@@ -108,6 +301,108 @@ fn main() -> rusqlite::Result<()> {
             (@arg id: +multiple index(2) {is_node}
                 "The node ids. Can also specify multiple nodes. \
                 If not given, will read from stdin")
+        ) (@subcommand exec =>
+            (about: "Executes a list of commands in a single transaction")
+            (after_help: "Commands are given one per line:\n    \
+                tag <id> <tags...>\n    \
+                untag <id> <tags...>\n    \
+                archive <ids...>\n    \
+                priority <id> <+N|-N>\n    \
+                rm <ids...>\n\
+                Empty lines and lines starting with '#' are ignored. \
+                Invalid commands are reported as 'line <n>: <error>' on \
+                stderr. If any command is invalid, no changes are applied, \
+                otherwise every command is reported as 'line <n>: ok' on \
+                stdout once all of them are applied.")
+            (@arg file: +required index(1)
+                "File to read the commands from, '-' for stdin")
+        ) (@subcommand dups =>
+            (about: "Lists groups of nodes with identical content, one group per line")
+            (@arg ignore_whitespace: -w --("ignore-whitespace")
+                "Ignore whitespace differences when comparing contents")
+            (@arg merge: -m --merge
+                "Interactively choose a node to keep for each group. \
+                It receives the tags of the others, which are removed")
+        ) (@subcommand tags =>
+            (about: "Lists all tags with the number of nodes using them")
+            (alias: "tag")
+            (@arg depth: -d --depth +takes_value {is_uint}
+                "Collapse dotted tags like 'proj.alpha' to their first \
+                <depth> components")
+            (@subcommand merge =>
+                (about: "Merges tags into another tag")
+                (@arg from: +required +multiple index(1) "The tags to merge")
+                (@arg into: +required index(2) "The tag to merge them into")
+            )
+            (@subcommand rename =>
+                (about: "Renames a tag on all nodes")
+                (@arg from: +required index(1) "The current name of the tag")
+                (@arg to: +required index(2) "The new name of the tag")
+            )
+            (@subcommand prune =>
+                (about: "Removes the tags left behind by deleted nodes")
+                (@arg min_count: --("min-count") +takes_value {is_uint}
+                    "Also remove tags used on fewer than this many nodes")
+            )
+        ) (@subcommand config =>
+            (about: "Inspects the config file")
+            (setting: clap::AppSettings::SubcommandRequiredElseHelp)
+            (@subcommand path =>
+                (about: "Prints the path of the config file")
+            )
+            (@subcommand check =>
+                (about: "Loads the config file and prints the resolved \
+                    storages and programs or what is wrong with it")
+            )
+        ) (@subcommand recent =>
+            (about: "Lists the most recently viewed nodes, \
+                same as 'ls -s viewed -n <count>'")
+            (@arg count: index(1) default_value("10") {is_uint}
+                "Number of nodes to list")
+        ) (@subcommand review =>
+            (about: "Opens the least recently viewed nodes one after another")
+            (@arg count: -n --count +takes_value {is_uint}
+                "Maximum number of nodes to review")
+        ) (@subcommand export =>
+            (about: "Writes nodes with all their data as JSON to stdout")
+            (after_help: "With --csv only the id, priority, archived state, \
+                tags (separated by ';') and the first content line are \
+                written, with a header row and CRLF line endings. With \
+                --format ndjson every node is written as a JSON object \
+                on its own line while they are read, which suits large \
+                storages.")
+            (@arg pattern: index(1)
+                "Only export nodes matching this pattern")
+            (@arg archived: --archived +takes_value default_value("all")
+                possible_values(ARCHIVED_VALUES)
+                "Which nodes to export: active | archived | all (default)")
+            (@arg csv: --csv "Write a CSV summary instead of JSON")
+            (@arg format: --format +takes_value conflicts_with[csv]
+                possible_values(&["json", "ndjson", "csv"])
+                "The output format: json (default, an array) | \
+                ndjson (one node per line) | csv (same as --csv)")
+        ) (@subcommand import =>
+            (about: "Adds the nodes from JSON or NDJSON written by export")
+            (after_help: "All nodes are imported in a single transaction, \
+                if one of them fails nothing is imported. Input that \
+                doesn't start with '[' is read as NDJSON.")
+            (@arg file: index(1)
+                "File to read the nodes from, stdin if not given or '-'")
+            (@arg preserve_ids: --("preserve-ids")
+                "Keep the original node ids. Fails if one of them is \
+                already in use. By default new ids are assigned")
+        ) (@subcommand stats =>
+            (about: "Prints an overview of the storage")
+        ) (@subcommand storages =>
+            (about: "Lists the configured storages with their paths. \
+                The default one is marked with '*'")
+        ) (@subcommand move =>
+            (about: "Moves nodes into another storage, prints their new ids")
+            (alias: "mv")
+            (@arg to: -t --to +required +takes_value "The target storage")
+            (@arg id: +multiple index(1) {is_node}
+                "The node ids. Can also specify multiple nodes. \
+                If not given, will read from stdin")
         ) (@subcommand archive =>
            (about: "Toggles the archived state of a node")
            (alias: "a")
@@ -117,42 +412,113 @@ fn main() -> rusqlite::Result<()> {
         )
     ).get_matches();
 
-    let config = Config::load_default().expect("Error loading config");
-    let mut storage_path = match matches.value_of("storage") {
-        Some(name) => match config.storage_folder(name) {
+    if matches.is_present("verbose") {
+        log::set_level(log::VERBOSE);
+    } else if matches.is_present("quiet") {
+        log::set_level(log::QUIET);
+    }
+
+    // has to work with an invalid config
+    if let ("config", Some(s)) = matches.subcommand() {
+        std::process::exit(commands::config(s));
+    }
+
+    let config = match Config::load_default() {
+        Ok(config) => config,
+        Err(err) => {
+            print_config_error(&err);
+            std::process::exit(1);
+        }
+    };
+    let mut edit_options = util::EditOptions::new(&config);
+    if let Some(editor) = matches.value_of("editor") {
+        let editor: Vec<String> = editor.split_whitespace()
+            .map(String::from)
+            .collect();
+        if editor.is_empty() {
+            eprintln!("The editor command can't be empty");
+            std::process::exit(1);
+        }
+        edit_options.editor = editor;
+    }
+    // doesn't need a storage, opening it would create its database
+    if let ("storages", Some(_)) = matches.subcommand() {
+        std::process::exit(commands::storages(&config));
+    }
+
+    let memory = matches.is_present("memory");
+    let storage = if memory {
+        // not a valid storage name, so never confused with a real one
+        ":memory:"
+    } else {
+        matches.value_of("storage").unwrap_or(config.default_storage())
+    };
+    let conn = if memory {
+        verbose!("Using an in-memory storage");
+        nodes::db::open_memory()
+    } else {
+        let mut storage_path = match config.storage_folder(storage) {
             Some(path) => path.clone(),
             None => {
-                println!("Storage '{}' unknown", name);
+                println!("Storage '{}' unknown", storage);
                 std::process::exit(1);
             }
-        }, None => config.default_storage_folder().clone(),
+        };
+        storage_path.push("nodes.db");
+        verbose!("Using storage '{}' at {}", storage, storage_path.display());
+
+        let conn = match util::open_db(&storage_path) {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("Failed to open storage '{}': {}", storage, err);
+                std::process::exit(1);
+            }
+        };
+        // off by default: drastically improves performance, especially on hdds
+        // e.g. creation time goes down from "about a seond" to
+        // "feels like immediately" on my old hdd.
+        // no noticable performance difference when nodes.db is stored
+        // on an ssd or ramdisk
+        let synchronous = config.synchronous_mode().unwrap_or("off");
+        verbose!("Synchronous mode: {}", synchronous);
+        conn.pragma_update(None, "SYNCHRONOUS", &synchronous)?;
+        conn
     };
-    storage_path.push("nodes.db");
 
-    let conn: rusqlite::Connection = Connection::open(storage_path)?;
-    // XXX: this may not be desired by all users, make it configurable
-    // drastically improves performance, especially on hdds
-    // e.g. creation time goes down from "about a seond" to
-    // "feels like immediately" on my old hdd.
-    // no noticable performance difference when nodes.db is stored
-    // on an ssd or ramdisk
-    conn.pragma_update(None, "SYNCHRONOUS", &0).unwrap();
+    // otherwise the tags of deleted nodes are kept
+    nodes::db::enable_foreign_keys(&conn)?;
 
-    // TODO: if database is empty, create tables
-    // maybe only check whether or not file already exists?
-    // and how to upgrade to a new schema? store version?
+    // creates the tables on a new storage and upgrades old ones
+    if let Err(err) = init_database(&conn) {
+        eprintln!("Failed to initialize the database: {}", err);
+        std::process::exit(1);
+    }
 
     let r = match matches.subcommand() {
-        ("rm", Some(s)) => commands::rm(&conn, s),
-        ("edit", Some(s)) => commands::edit(&conn, s),
-        ("create", Some(s)) => commands::create(&conn, s),
-        ("ls", Some(s)) => commands::ls(&conn, s),
-        ("select", Some(s)) => select::select(&conn, s),
-        ("output", Some(s)) => commands::output(&conn, s),
+        ("rm", Some(s)) => commands::rm(&conn, &config, s),
+        ("edit", Some(s)) => commands::edit(&conn, &edit_options, s),
+        ("create", Some(s)) => commands::create(&conn, &edit_options, s),
+        ("ls", Some(s)) => commands::ls(&conn, &config, storage, s),
+        ("select", Some(s)) => select::select(&conn, &config, storage,
+            &edit_options, s),
+        ("output", Some(s)) => commands::output(&conn, &config, s),
+        ("append", Some(s)) => commands::append(&conn, s, false),
+        ("prepend", Some(s)) => commands::append(&conn, s, true),
         ("addtag", Some(s)) => commands::add_tag(&conn, s),
         ("rmtag", Some(s)) => commands::remove_tag(&conn, s),
         ("archive", Some(s)) => commands::archive(&conn, s),
-        _ => select::select(&conn, &clap::ArgMatches::default())
+        ("priority", Some(s)) => commands::priority(&conn, s),
+        ("exec", Some(s)) => commands::exec(&conn, s),
+        ("dups", Some(s)) => commands::dups(&conn, &config, s),
+        ("tags", Some(s)) => commands::tags(&conn, s),
+        ("recent", Some(s)) => commands::recent(&conn, &config, s),
+        ("review", Some(s)) => commands::review(&conn, &edit_options, s),
+        ("export", Some(s)) => commands::export(&conn, &config, storage, s),
+        ("import", Some(s)) => commands::import(&conn, s),
+        ("stats", Some(_)) => commands::stats(&conn),
+        ("move", Some(s)) => commands::move_nodes(&conn, &config, storage, s),
+        _ => select::select(&conn, &config, storage, &edit_options,
+            &clap::ArgMatches::default())
     };
 
     std::process::exit(r);
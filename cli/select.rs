@@ -1,11 +1,13 @@
 use super::util;
+use super::input::{LineInput, History};
 use nodes::pattern;
+use nodes::Config;
 
-use std::{cmp, io, thread};
+use std::{cmp, thread};
 use std::sync::{Mutex, Arc};
 use std::io::prelude::*;
 use std::io::BufWriter;
-use std::time::Duration;
+use std::path::PathBuf;
 
 use termion::event::Key;
 use termion::input::TermRead;
@@ -14,6 +16,29 @@ use signal_hook::{iterator::Signals, SIGWINCH};
 
 use rusqlite::Connection;
 use scopeguard::defer;
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use unicode_width::UnicodeWidthStr;
+
+// number of content chars loaded for the summaries, more won't
+// fit on any reasonable terminal
+const SUMMARY_PREFIX: usize = 1000;
+
+// the preview pane is hidden on terminals narrower than this
+const PREVIEW_MIN_WIDTH: u16 = 80;
+
+// the summary column never gets narrower than this when tags are shown
+const SUMMARY_MIN_WIDTH: usize = 60;
+
+// number of node contents kept for the preview pane
+const PREVIEW_CACHE_SIZE: usize = 16;
+
+// names of the node label colors, in ansi order. Each of them can be
+// prefixed with 'light' for the bright variant, e.g. 'lightblack'
+// (gray) or 'lightwhite'. Not used on the hovered row, whose
+// background might hide them
+const LABEL_COLORS: [&str; 8] = ["black", "red", "green", "yellow",
+    "blue", "magenta", "cyan", "white"];
 
 #[derive(Clone)]
 struct SelectNode {
@@ -22,6 +47,7 @@ struct SelectNode {
     summary: String,
     selected: bool,
     tags: Vec<String>,
+    color: Option<u8>, // ansi value of the label color
 }
 
 enum State {
@@ -29,25 +55,52 @@ enum State {
     Search,
     Command,
     Delete,
+    Find,
 }
 
 struct SelectScreen<W: Write> {
-    args: util::ListArgs, // invariant: pattern always Some
+    args: util::QueryArgs, // invariant: pattern always Some
+    base_pattern: Option<pattern::CondNode>, // from the command line
     nodes: Vec<SelectNode>,
     hover: usize, // index of node the cursor is over
     start: usize, // in of first node currently displayed
-    termsize: (u16, u16), // TODO: handle SIGWINCH as resize handler
-    pattern: String, // current search filter
+    termsize: (u16, u16), // updated on SIGWINCH
+    pattern: LineInput, // current search filter
+    pattern_error: Option<pattern::ParseError>, // why the filter is invalid
+    find: LineInput, // term to jump to in the shown nodes
+    find_term: Option<String>, // last entered find term, for n/N
+    show_ids: bool, // whether the rows start with the node ids, toggled via #
     screen: W,
     state: State,
+    // rows as last written to the screen, only changed rows are
+    // rewritten. Cleared to force a full redraw.
+    drawn: Vec<String>,
 
     // config
     cursor_off: usize,
+    edit_options: util::EditOptions,
+    preview: bool, // show the content of the hovered node on the right
+    clipboard: Option<Vec<String>>, // None if there is no clipboard program
+    tag_ratio: f64, // share of the list width used for tags
+    tag_min_width: usize, // tags are only shown in lists wider than this
+    strip_headings: bool, // strip markdown markers from the summaries
+
+    // preview pane
+    preview_cache: Vec<(u32, String)>, // most recently used last
+    preview_id: Option<u32>, // node shown in the preview
+    preview_scroll: usize, // first shown (wrapped) line
 
     // state stuff
     delete_hover: bool,
     delete_sel: Vec<u32>,
-    command: String,
+    command: LineInput,
+    search_history: History,
+    command_history: History,
+    known_tags: Vec<String>, // loaded when entering command mode
+    completions: Vec<String>, // tags completing the last command word
+    completion: Option<usize>, // completion inserted via tab
+    undo: Vec<Vec<util::ExportNode>>, // deleted nodes, last delete last
+    status: Option<String>, // message shown until the next key
     action_count: usize,
     gpending: bool,
 }
@@ -58,30 +111,60 @@ const BG_RESET: termion::color::Bg<termion::color::Reset> =
     termion::color::Bg(termion::color::Reset);
 
 impl<W: Write> SelectScreen<W> {
-    pub fn new(conn: &Connection, args: &clap::ArgMatches, screen: W)
+    pub fn new(conn: &Connection, config: &Config, storage: &str,
+            edit_options: &util::EditOptions, args: &clap::ArgMatches,
+            ids: Option<Vec<u32>>, termsize: (u16, u16), screen: W)
             -> SelectScreen<W> {
 
+        let mut list_args = util::extract_list_args(&args, config, storage,
+            true, true);
+        list_args.ids = ids;
+        list_args.content_prefix = Some(SUMMARY_PREFIX);
+
         let mut s = SelectScreen {
-            args: util::extract_list_args(&args, true, true),
+            base_pattern: list_args.pattern.clone(),
+            args: list_args,
             nodes: Vec::new(),
             hover: 0,
             start: 0,
-            termsize: util::terminal_size(),
-            pattern: String::new(),
+            termsize: termsize,
+            pattern: LineInput::new(),
+            pattern_error: None,
+            find: LineInput::new(),
+            find_term: None,
+            show_ids: true,
             state: State::Normal,
             screen: screen,
+            drawn: Vec::new(),
             cursor_off: 20,
+            edit_options: edit_options.clone(),
+            preview: config.select_preview(),
+            clipboard: util::resolve_clipboard(config),
+            tag_ratio: config.tag_column_ratio(),
+            tag_min_width: config.tag_column_min_width(),
+            strip_headings: config.strip_heading_markers(),
+
+            preview_cache: Vec::new(),
+            preview_id: None,
+            preview_scroll: 0,
 
             delete_hover: false,
             delete_sel: Vec::new(),
-            command: String::new(),
+            command: LineInput::new(),
+            search_history: History::load(history_path("search_history")),
+            command_history: History::load(history_path("command_history")),
+            known_tags: Vec::new(),
+            completions: Vec::new(),
+            completion: None,
+            undo: Vec::new(),
+            status: None,
             action_count: 0,
             gpending: false,
         };
 
         // initial load and render
         s.reload_nodes(conn);
-        s.render();
+        s.render(conn);
         s
     }
 
@@ -92,11 +175,15 @@ impl<W: Write> SelectScreen<W> {
             .map(|node| node.id)
             .collect();
 
+        // contents may have changed
+        self.drawn.clear();
+        self.preview_cache.clear();
+        let strip = self.strip_headings;
         let mut nodes = Vec::new();
-        util::iter_nodes(conn, &self.args, |node| {
-            // we use the whole first line as summary since we don't reload
-            // the summary on every terminal resize
-            let summary = node.content.lines().next().unwrap_or("").to_string();
+        let res = util::iter_nodes(conn, &self.args, |node| {
+            // we use the whole loaded first line as summary since we
+            // don't reload the summary on every terminal resize
+            let summary = util::title_line(&node.content, strip).to_string();
             let tags = node.tags.iter().map(|s| s.to_string()).collect();
             nodes.push(SelectNode{
                 id: node.id,
@@ -104,87 +191,248 @@ impl<W: Write> SelectScreen<W> {
                 summary: summary,
                 selected: selected.contains(&node.id),
                 tags: tags,
+                color: node.color.and_then(label_color),
             });
         });
         self.nodes = nodes;
+        if let Err(err) = res {
+            self.status = Some(format!("Failed to load nodes: {}", err));
+        }
+
+        if let Some(query) = self.fuzzy_query() {
+            let query = query.to_string();
+            self.rank_fuzzy(&query);
+        }
+    }
+
+    // Returns the fuzzy query if the search is in fuzzy mode, i.e.
+    // starts with '~'. In that case, no sql pattern is used.
+    fn fuzzy_query(&self) -> Option<&str> {
+        if self.pattern.text().starts_with('~') {
+            Some(&self.pattern.text()[1..])
+        } else {
+            None
+        }
+    }
+
+    // Removes all nodes whose summary doesn't fuzzy-match the given query
+    // and sorts the remaining ones by their score.
+    fn rank_fuzzy(&mut self, query: &str) {
+        if query.is_empty() {
+            return;
+        }
+
+        let matcher = SkimMatcherV2::default();
+        let mut scored: Vec<(i64, SelectNode)> = self.nodes.drain(..)
+            .filter_map(|node| matcher.fuzzy_match(&node.summary, query)
+                .map(|score| (score, node)))
+            .collect();
+        scored.sort_by(|a, b| b.0.cmp(&a.0));
+        self.nodes = scored.into_iter().map(|(_, node)| node).collect();
     }
 
+    // Returns whether the nodes have to be reloaded.
+    // Invalid patterns keep the last valid filter, the error is
+    // shown in the search bar.
     pub fn reparse_pattern(&mut self) -> bool {
+        self.pattern_error = None;
+        if self.fuzzy_query().is_some() {
+            // fuzzy matching is done on the loaded nodes, so we
+            // always have to reload them only filtered by the
+            // command line pattern
+            self.args.pattern = self.base_pattern.clone();
+            return true;
+        }
+
         if self.pattern.is_empty() {
-            let changed = self.args.pattern.is_some();
-            self.args.pattern = None;
-            return changed;
+            let old = self.args.pattern.as_ref().map(pattern::tosql);
+            self.args.pattern = self.base_pattern.clone();
+            return old != self.args.pattern.as_ref().map(pattern::tosql);
         }
 
-        match pattern::parse_condition(&self.pattern) {
-            Err(_) => {
-                // TODO: log invalid pattern? show it somewhere?
-                // some kind of visual feedback? maybe merk it red?
+        match pattern::parse_condition(self.pattern.text()) {
+            Err(err) => {
+                self.pattern_error = Some(err);
                 false
             }, Ok(pattern) => {
-                self.args.pattern = Some(pattern);
+                let base = self.base_pattern.clone();
+                self.args.pattern = pattern::all_of(
+                    base.into_iter().chain(Some(pattern)).collect());
                 true
             }
         }
     }
 
-    // renders without flush
-    pub fn render_nf(&mut self) {
+    // Whether the preview pane is shown, it needs a wide enough terminal
+    fn show_preview(&self) -> bool {
+        self.preview && self.termx() >= PREVIEW_MIN_WIDTH
+    }
+
+    // Width of the node list, the rest is used by the preview pane
+    fn list_width(&self) -> usize {
+        if self.show_preview() {
+            (self.termx() / 2) as usize
+        } else {
+            self.termx() as usize
+        }
+    }
+
+    // Makes sure the content of the hovered node is in the preview
+    // cache. Contents are loaded lazily, only when hovered.
+    fn update_preview(&mut self, conn: &Connection) {
+        if !self.show_preview() || self.nodes.is_empty() {
+            return;
+        }
+
+        let id = self.nodes[self.hover].id;
+        if self.preview_id != Some(id) {
+            self.preview_id = Some(id);
+            self.preview_scroll = 0;
+        }
+
+        if let Some(pos) = self.preview_cache.iter().position(|e| e.0 == id) {
+            let entry = self.preview_cache.remove(pos);
+            self.preview_cache.push(entry);
+            return;
+        }
+
+        let content = conn.query_row(
+            "SELECT content FROM nodes WHERE id = ?1", &[id],
+            |row| row.get::<_, String>(0)).unwrap_or_default();
+        if self.preview_cache.len() == PREVIEW_CACHE_SIZE {
+            self.preview_cache.remove(0);
+        }
+        self.preview_cache.push((id, content));
+    }
+
+    // The lines of the preview pane, wrapped to the given width
+    fn preview_lines(&self, width: usize) -> Vec<String> {
+        let content = match self.preview_cache.last() {
+            Some((id, content)) if Some(*id) == self.preview_id => content,
+            _ => return Vec::new(),
+        };
+
+        content.lines()
+            .flat_map(|line| util::wrap_line(&line.replace('\t', "    "), width))
+            .collect()
+    }
+
+    // The list part of the row for the node with the given index
+    fn node_line(&self, i: usize, width: usize) -> String {
         let bg_current = termion::color::Bg(termion::color::LightGreen);
         let fg_selected = termion::color::Fg(termion::color::LightRed);
-        let x = 1;
+        let node = &self.nodes[i];
 
-        let mut y = 1;
-        let mut i = self.start;
-        for node in self.nodes[self.start..].iter() {
-            if y > self.termy() {
-                break;
-            }
+        let bg = if i == self.hover {
+            bg_current.to_string()
+        } else {
+            BG_RESET.to_string()
+        };
 
-            if i == self.hover {
-                write!(self.screen, "{}", bg_current).unwrap();
-            } else {
-                write!(self.screen, "{}", BG_RESET).unwrap();
-            }
+        let fg = if node.selected {
+            fg_selected.to_string()
+        } else if i == self.hover {
+            FG_RESET.to_string()
+        } else if let Some(color) = node.color {
+            termion::color::Fg(termion::color::AnsiValue(color)).to_string()
+        } else {
+            FG_RESET.to_string()
+        };
+
+        // the id prefix and the space before the tags
+        let idstr = if self.show_ids {
+            format!("{}: ", node.id)
+        } else {
+            String::new()
+        };
+        let width = width.saturating_sub(idstr.len() + 1);
+        let mut sumwidth = width;
+        let mut tagswidth = 0;
+        if width > self.tag_min_width {
+            let rest = (width as f64 * (1.0 - self.tag_ratio)) as usize;
+            sumwidth = cmp::min(width, cmp::max(SUMMARY_MIN_WIDTH, rest));
+            tagswidth = width - sumwidth;
+        }
+
+        let tags = util::pack_tags(node.priority, &node.tags, tagswidth);
+        let tags = util::short_string(&tags, tagswidth);
+
+        // shorten, maybe terminal was resized since then
+        let summary = util::short_string(&node.summary, sumwidth);
+
+        // padded manually, format counts chars instead of columns
+        // TODO: clear line first?
+        format!("{}{}{}{}{} {}{}",
+            bg, fg, idstr,
+            summary, " ".repeat(sumwidth.saturating_sub(summary.width())),
+            " ".repeat(tagswidth.saturating_sub(tags.width())), tags)
+    }
+
+    // renders without flush
+    pub fn render_nf(&mut self) {
+        let x = 1;
+        let overlay = match self.state {
+            State::Normal => self.status.is_some(),
+            _ => true,
+        };
+
+        // with the preview pane every row is drawn since the
+        // pane continues below the nodes
+        let preview = self.show_preview();
+        let listw = self.list_width();
+        let previeww = (self.termx() as usize).saturating_sub(listw + 1);
+        let preview_lines = if preview {
+            self.preview_lines(previeww)
+        } else {
+            Vec::new()
+        };
+        // don't scroll past the last line
+        self.preview_scroll = cmp::min(self.preview_scroll,
+            preview_lines.len().saturating_sub(1));
 
-            if node.selected {
-                write!(self.screen, "{}", fg_selected).unwrap();
+        let mut y = 1;
+        while y <= self.termy() {
+            let i = self.start + (y - 1) as usize;
+            let mut line = if i < self.nodes.len() {
+                self.node_line(i, listw)
+            } else if preview {
+                format!("{}{}{:w$}", BG_RESET, FG_RESET, "", w = listw)
             } else {
-                write!(self.screen, "{}", FG_RESET).unwrap();
+                break;
+            };
+
+            if preview {
+                let text = preview_lines.get(self.preview_scroll + (y - 1) as usize)
+                    .map(|l| l.as_str())
+                    .unwrap_or("");
+                // padded by display width, format pads by chars
+                line += &format!("{}{}\u{2502}{}{:w$}", BG_RESET, FG_RESET,
+                    text, "", w = previeww.saturating_sub(text.width()));
             }
 
-            let idstr = node.id.to_string();
-            let width = (self.termx() as usize) - idstr.len() - 3;
-            let mut sumwidth = width;
-            let mut tagswidth = 0;
-            // TODO: don't hardcode distribution
-            if width > 80 {
-                sumwidth = cmp::max(60, (width as f64 * 0.7) as usize);
-                tagswidth = width - sumwidth;
+            // The last row is covered by the state line. An empty entry
+            // never matches, so it is redrawn once the state line is gone
+            let covered = overlay && y == self.termy();
+            if covered {
+                line.clear();
             }
 
-            // let mut tags = String::new();
-            let mut tags = format!("({})", node.priority);
-            if tagswidth > 0 && !node.tags.is_empty() {
-                tags += &("[".to_string() + &node.tags.join("][") + "]");
+            // only write rows that changed since the last render
+            let row = (y - 1) as usize;
+            if self.drawn.get(row) != Some(&line) {
+                if !covered {
+                    write!(self.screen, "{}{}",
+                        termion::cursor::Goto(x, y), line).unwrap();
+                }
 
-                // tags = util::short_string(&tags, tagswidth);
-                // TODO: only show tags that can be completely shown
-                // and are not cut off (maybe add [...] in the case
-                // that there are remaining tags?)
+                if row < self.drawn.len() {
+                    self.drawn[row] = line;
+                } else {
+                    self.drawn.push(line);
+                }
             }
 
-            // shorten, maybe terminal was resized since then
-            let summary = util::short_string(&node.summary, sumwidth);
-
-            // TODO: clear line first?
-            write!(self.screen, "{}{}: {:<sw$} {:>tw$.tw$}",
-                termion::cursor::Goto(x, y),
-                node.id, summary, tags,
-                sw = sumwidth, tw = tagswidth).unwrap();
-
             y += 1;
-            i += 1;
         }
 
         // clear remaining screen
@@ -194,17 +442,35 @@ impl<W: Write> SelectScreen<W> {
                 BG_RESET, FG_RESET,
                 termion::clear::AfterCursor).unwrap();
         }
+        self.drawn.truncate((y - 1) as usize);
 
         // render special state-dependent stuff
         match self.state {
             State::Command => self.render_command(),
             State::Delete => self.render_delete(),
+            State::Find => self.render_find(),
             State::Search => self.render_search(),
-            _ => (),
+            State::Normal => self.render_status(),
         };
     }
 
-    pub fn render(&mut self) {
+    fn render_status(&mut self) {
+        if let Some(status) = &self.status {
+            write!(self.screen, "{}{}{}{}{}",
+                termion::cursor::Goto(1, self.termy()),
+                termion::clear::CurrentLine,
+                FG_RESET, BG_RESET, status).unwrap();
+        }
+    }
+
+    // Loads the preview if needed and renders
+    pub fn render(&mut self, conn: &Connection) {
+        self.update_preview(conn);
+        self.draw();
+    }
+
+    // renders with what is already loaded, e.g. on resize
+    pub fn draw(&mut self) {
         self.render_nf();
         self.screen.flush().unwrap();
     }
@@ -262,6 +528,24 @@ impl<W: Write> SelectScreen<W> {
         self.correct_hover();
     }
 
+    // Scrolls n pages down, the cursor moves along.
+    // The last page is kept full when possible.
+    pub fn page_down(&mut self, n: usize) {
+        let page = ((self.termy() - 1) as usize).saturating_mul(n);
+        let max_start = self.nodes.len().saturating_sub(self.termy() as usize);
+        self.start = cmp::min(self.start.saturating_add(page), max_start);
+        self.hover = self.hover.saturating_add(page);
+        self.correct_hover();
+    }
+
+    // Scrolls n pages up, the cursor moves along
+    pub fn page_up(&mut self, n: usize) {
+        let page = ((self.termy() - 1) as usize).saturating_mul(n);
+        self.start = self.start.saturating_sub(page);
+        self.hover = self.hover.saturating_sub(page);
+        self.correct_hover();
+    }
+
     // The bool returns whether the hovered node is returned instead
     // of the selection
     pub fn selection_or_hover(&self) -> (Vec<u32>, bool) {
@@ -282,21 +566,22 @@ impl<W: Write> SelectScreen<W> {
         let (selected, hovered) = self.selection_or_hover();
         if hovered {
             util::toggle_archived(conn, selected[0]).unwrap();
-            if self.args.archived.is_some() {
+            if self.args.archived != util::ArchivedFilter::All {
                 self.nodes.remove(self.hover);
             }
             return;
         }
 
         util::toggle_archived_range(conn, &selected).unwrap();
-        if self.args.archived.is_some() {
+        if self.args.archived != util::ArchivedFilter::All {
             self.nodes.retain(|node| !node.selected);
         }
     }
 
     pub fn resized(&mut self, size: (u16, u16)) {
         self.termsize = size;
-        self.render();
+        self.drawn.clear();
+        self.draw();
     }
 
     // Returns whether another iteration should be done, i.e. returns
@@ -307,14 +592,18 @@ impl<W: Write> SelectScreen<W> {
             State::Search => self.input_search(key, conn),
             State::Command => self.input_cmd(key, conn),
             State::Delete => self.input_delete(key, conn),
+            State::Find => self.input_find(key, conn),
         }
     }
 
     fn next_sort_mode(&mut self) {
         self.args.sort = match &self.args.sort {
-            Some(util::Sort::ID) => { Some(util::Sort::Edited) },
-            Some(util::Sort::Edited) => { Some(util::Sort::Priority) },
-            Some(util::Sort::Priority) => { Some(util::Sort::ID) },
+            Some(util::Sort::ID) => { Some(util::Sort::Created) },
+            Some(util::Sort::Created) => { Some(util::Sort::Edited) },
+            Some(util::Sort::Edited) => { Some(util::Sort::Viewed) },
+            Some(util::Sort::Viewed) => { Some(util::Sort::Priority) },
+            Some(util::Sort::Priority) => { Some(util::Sort::Random) },
+            Some(util::Sort::Random) => { Some(util::Sort::ID) },
             None => None,
         };
     }
@@ -334,6 +623,8 @@ impl<W: Write> SelectScreen<W> {
         let mut reset_acount = true;
         let mut reset_gpending = true;
         let mut changed = true;
+        // the status is only shown until the next key
+        let had_status = self.status.take().is_some();
         match key {
             Key::Char('q') => { // quit
                 return false;
@@ -344,35 +635,55 @@ impl<W: Write> SelectScreen<W> {
             Key::Char('k') | Key::Up => { // up
                 self.cursor_up(cmp::max(self.action_count, 1));
             },
-            Key::Char('G') | Key::End => { // end of list
-                self.hover = self.nodes.len() - 1;
-                self.start = self.hover.saturating_sub(
-                    (self.termy() - 1) as usize);
+            Key::PageDown | Key::Ctrl('f') => { // page down
+                self.page_down(cmp::max(self.action_count, 1));
+            },
+            Key::PageUp | Key::Ctrl('b') => { // page up
+                self.page_up(cmp::max(self.action_count, 1));
+            },
+            Key::Char('G') | Key::End => { // end of list or node [count]
+                self.hover = match self.action_count {
+                    0 => self.nodes.len().saturating_sub(1),
+                    n => n - 1,
+                };
+                self.correct_hover();
             },
             Key::Home => { // beginning of list, like gg
                 self.start = 0;
                 self.hover = 0;
             },
-            Key::Char('g') => { // beginning of list; gg detection
+            Key::Char('g') => { // beginning of list or node [count]; gg detection
                 if self.gpending {
-                    self.start = 0;
-                    self.hover = 0;
+                    self.hover = self.action_count.saturating_sub(1);
+                    self.correct_hover();
                 } else {
                     self.gpending = true;
                     reset_gpending = false;
+                    reset_acount = false;
                     changed = false;
                 }
             },
             Key::Char(' ') if !self.nodes.is_empty() => { // toggle selection
                 self.nodes[self.hover].selected ^= true;
             },
-            Key::Char('e') | Key::Char('\n') if !self.nodes.is_empty() => { // edit
-                write!(self.screen, "{}", termion::screen::ToMainScreen).unwrap();
-                util::edit(conn, self.nodes[self.hover].id).unwrap();
-                write!(self.screen, "{}{}{}",
-                    termion::screen::ToAlternateScreen,
-                    termion::clear::All,
-                    termion::cursor::Hide).unwrap();
+            Key::Char('e') | Key::Char('\n') if !self.nodes.is_empty() => {
+                // edit the selected nodes one after another
+                let (ids, _) = self.selection_or_hover();
+                for id in ids {
+                    write!(self.screen, "{}", termion::screen::ToMainScreen).unwrap();
+                    self.screen.flush().unwrap();
+                    let res = util::edit(conn, &self.edit_options, id, None);
+                    write!(self.screen, "{}{}{}",
+                        termion::screen::ToAlternateScreen,
+                        termion::clear::All,
+                        termion::cursor::Hide).unwrap();
+                    self.screen.flush().unwrap();
+                    if let Err(err) = res {
+                        self.status = Some(format!("Failed to edit node {}: {}",
+                            id, err));
+                        break;
+                    }
+                }
                 self.reload_nodes(conn);
             },
             Key::Char('c') => {
@@ -381,7 +692,7 @@ impl<W: Write> SelectScreen<W> {
                 // could display it with timeout (like 1 or 2 seconds)
                 // we wouldn't need an extra thread for that, enough to
                 // check on user input
-                match util::create(conn, None) {
+                match util::create(conn, &self.edit_options, None) {
                     Ok(_) => (),
                     Err(err) => {
                         eprintln!("{}", err);
@@ -400,11 +711,23 @@ impl<W: Write> SelectScreen<W> {
                 reset_acount = false;
                 changed = false;
             },
+            Key::Char('#') => { // toggle ids
+                self.show_ids = !self.show_ids;
+            },
             Key::Char('a') => { // archive
                 self.archive(conn);
             },
+            Key::Char('P') if !self.nodes.is_empty() => { // toggle pinned
+                let hover = self.nodes[self.hover].id;
+                let (nodes, _) = self.selection_or_hover();
+                util::toggle_pinned(conn, &nodes).unwrap();
+                self.reload_nodes(conn);
+                self.set_hover_to_id(hover);
+            },
             Key::Char('r') => { // reload
-                self.termsize = util::terminal_size();
+                if let Ok(size) = util::terminal_size() {
+                    self.termsize = size;
+                }
                 self.reload_nodes(conn);
             },
             Key::Char('s') => { // clear selection
@@ -423,9 +746,41 @@ impl<W: Write> SelectScreen<W> {
                 // enter search mode
                 self.state = State::Search;
             },
+            Key::Char('?') => { // find in the shown nodes
+                self.state = State::Find;
+            },
+            Key::Char('n') => { // next find match
+                for _ in 0..cmp::max(self.action_count, 1) {
+                    self.find_next(true);
+                }
+            },
+            Key::Char('N') => { // previous find match
+                for _ in 0..cmp::max(self.action_count, 1) {
+                    self.find_next(false);
+                }
+            },
+            // add a line to the hovered node via the command line
+            Key::Char('o') | Key::Char('O') if !self.nodes.is_empty() => {
+                let cmd = if key == Key::Char('o') { "append " } else { "prepend " };
+                self.command.set(cmd);
+                self.completions.clear();
+                self.state = State::Command;
+            },
             Key::Char(':') => {
+                // for completion, not updated while in command mode
+                self.known_tags = util::list_tags(conn)
+                    .map(|tags| tags.into_iter().map(|(tag, _)| tag).collect())
+                    .unwrap_or_default();
+                self.update_completions();
                 self.state = State::Command;
             },
+            Key::Ctrl('d') if self.show_preview() => { // scroll preview down
+                self.preview_scroll += (self.termy() / 2) as usize;
+            },
+            Key::Ctrl('u') if self.show_preview() => { // scroll preview up
+                self.preview_scroll = self.preview_scroll
+                    .saturating_sub((self.termy() / 2) as usize);
+            },
             Key::Ctrl('o') => {
                 self.next_sort_mode();
                 self.reload_nodes(conn);
@@ -444,11 +799,28 @@ impl<W: Write> SelectScreen<W> {
                 self.reload_nodes(conn);
                 self.set_hover_to_id(hover);
             },
+            Key::Char('y') if !self.nodes.is_empty() => { // copy ids
+                let (ids, _) = self.selection_or_hover();
+                let text: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+                let msg = match ids.len() {
+                    1 => format!("Copied id {}", ids[0]),
+                    n => format!("Copied {} ids", n),
+                };
+                self.copy(&text.join("\n"), msg);
+            },
+            Key::Char('Y') if !self.nodes.is_empty() => { // copy content
+                let id = self.nodes[self.hover].id;
+                match util::fetch_node_full(conn, id) {
+                    Ok(node) => self.copy(&node.content,
+                        format!("Copied content of node {}", id)),
+                    Err(err) => self.status = Some(err.to_string()),
+                }
+            },
+            Key::Char('u') => { // undo last delete
+                self.undo_delete(conn);
+            },
             // TODO:
-            // - page down/up
-            // - allow to open/show multiple at once?
-            //   maybe allow to edit/show selected?
-            // - "u": undo?
+            // - allow to show multiple at once?
             _ => changed = false,
         }
 
@@ -461,47 +833,168 @@ impl<W: Write> SelectScreen<W> {
         }
 
         // re-render whole screen
-        if changed {
-            self.render();
+        if changed || had_status {
+            self.render(conn);
         }
 
         true
     }
 
+    // Copies text to the clipboard, shows msg on success
+    fn copy(&mut self, text: &str, msg: String) {
+        self.status = Some(match &self.clipboard {
+            None => "No clipboard program found, set programs.clipboard"
+                .to_string(),
+            Some(clipboard) => match util::copy_to_clipboard(clipboard, text) {
+                Ok(()) => msg,
+                Err(err) => format!("Failed to copy: {}", err),
+            },
+        });
+    }
+
+    // Restores the nodes removed by the last delete
+    fn undo_delete(&mut self, conn: &Connection) {
+        let nodes = match self.undo.pop() {
+            Some(nodes) => nodes,
+            None => {
+                self.status = Some("Nothing to undo".to_string());
+                return;
+            }
+        };
+
+        self.status = Some(match util::restore_nodes(conn, &nodes) {
+            Ok((_, true)) if nodes.len() == 1 =>
+                format!("Restored node {}", nodes[0].id),
+            Ok((_, true)) => format!("Restored {} nodes", nodes.len()),
+            // a node created since then took one of the ids
+            Ok((ids, false)) if ids.len() == 1 => format!(
+                "Restored node {} as {}, its id was taken", nodes[0].id, ids[0]),
+            Ok((ids, false)) => format!(
+                "Restored {} nodes with new ids, some ids were taken", ids.len()),
+            Err(err) => format!("Undo failed: {}", err),
+        });
+        self.reload_nodes(conn);
+    }
+
+    // Moves the cursor to the next (or previous) node whose summary
+    // contains the find term, ignoring case. Wraps around.
+    fn find_next(&mut self, forward: bool) {
+        let term = match &self.find_term {
+            Some(term) => term.to_lowercase(),
+            None => {
+                self.status = Some("No find term, use ?".to_string());
+                return;
+            }
+        };
+
+        let n = self.nodes.len();
+        for step in 1..=n {
+            let i = if forward {
+                (self.hover + step) % n
+            } else {
+                (self.hover + n - step) % n
+            };
+
+            if self.nodes[i].summary.to_lowercase().contains(&term) {
+                if (forward && i <= self.hover) || (!forward && i >= self.hover) {
+                    self.status = Some("Find wrapped around".to_string());
+                }
+                self.hover = i;
+                self.correct_hover();
+                return;
+            }
+        }
+
+        self.status = Some(format!("Not found: {}", term));
+    }
+
+    fn render_find(&mut self) {
+        let y = self.termy();
+        self.find.render(&mut self.screen, "?", y);
+    }
+
+    pub fn input_find(&mut self, key: Key, conn: &Connection) -> bool {
+        let mut end = false;
+        match key {
+            Key::Esc | Key::Ctrl('c') | Key::Ctrl('d') => {
+                self.find.clear();
+                end = true;
+            },
+            Key::Backspace if self.find.is_empty() => {
+                end = true;
+            },
+            Key::Char('\n') => {
+                end = true;
+                let term = self.find.take();
+                if !term.is_empty() {
+                    self.find_term = Some(term);
+                    self.state = State::Normal;
+                    self.find_next(true);
+                }
+            },
+            key => {
+                self.find.input(key);
+            },
+        }
+
+        if end {
+            self.state = State::Normal;
+        }
+
+        self.render(conn);
+        true
+    }
+
     fn render_search(&mut self) {
-        write!(self.screen, "{}{}{}{}/{}",
-            termion::cursor::Goto(1, self.termy()),
-            termion::clear::CurrentLine,
-            termion::color::Fg(termion::color::Reset),
-            termion::color::Bg(termion::color::Reset),
-            self.pattern).unwrap();
+        let y = self.termy();
+        match &self.pattern_error {
+            None => self.pattern.render(&mut self.screen, "/", y),
+            Some(err) => {
+                let prefix = format!("{}/{}",
+                    termion::color::Fg(termion::color::LightRed), FG_RESET);
+                self.pattern.render(&mut self.screen, &prefix, y);
+                write!(self.screen, "  {}{}{}",
+                    termion::color::Fg(termion::color::LightRed),
+                    err, FG_RESET).unwrap();
+            }
+        }
     }
 
     pub fn input_search(&mut self, key: Key, conn: &Connection) -> bool {
-        let mut changed = true;
+        let mut changed = false;
+        let mut render = true;
         let mut end = false;
 
-        // TODO: cursor
-        // maybe general utility for line input?
         match key {
             Key::Esc | Key::Ctrl('c') | Key::Ctrl('d') => {
                 end = true;
+                changed = !self.pattern.is_empty();
                 self.pattern.clear();
             },
             Key::Char('\n') => {
                 end = true;
-                changed = false;
+                self.search_history.push(self.pattern.text());
             },
-            Key::Backspace => {
-                if self.pattern.pop().is_none() {
-                    end = true;
-                    changed = false;
+            Key::Backspace if self.pattern.is_empty() => {
+                end = true;
+            },
+            Key::Up | Key::Down => {
+                let entry = if key == Key::Up {
+                    self.search_history.prev(self.pattern.text())
+                } else {
+                    self.search_history.next()
+                };
+
+                if let Some(entry) = entry {
+                    changed = entry != self.pattern.text();
+                    self.pattern.set(entry);
                 }
             },
-            Key::Char(c) => {
-                self.pattern.push(c);
+            key => {
+                let old = self.pattern.text().to_string();
+                render = self.pattern.input(key);
+                changed = self.pattern.text() != old;
             },
-            _ => changed = false,
         }
 
         if changed {
@@ -519,8 +1012,8 @@ impl<W: Write> SelectScreen<W> {
             self.state = State::Normal;
         }
 
-        if changed || end {
-            self.render();
+        if render || end {
+            self.render(conn);
         }
 
         true
@@ -552,7 +1045,9 @@ impl<W: Write> SelectScreen<W> {
             },
             Key::Char('y') | Key::Char('Y') => {
                 end = true;
+                let backup = util::backup_nodes(conn, &self.delete_sel).unwrap();
                 util::delete_range(conn, &self.delete_sel).unwrap();
+                self.undo.push(backup);
                 if self.delete_hover {
                     self.nodes.remove(self.hover);
                 } else {
@@ -566,17 +1061,92 @@ impl<W: Write> SelectScreen<W> {
             self.state = State::Normal;
         }
 
-        self.render();
+        self.render(conn);
         true
     }
 
     fn render_command(&mut self) {
-        write!(self.screen, "{}{}{}{}:{}",
-            termion::clear::CurrentLine,
-            termion::cursor::Goto(1, self.termy()),
-            termion::color::Fg(termion::color::Reset),
-            termion::color::Bg(termion::color::Reset),
-            self.command).unwrap();
+        let y = self.termy();
+        self.command.render(&mut self.screen, ":", y);
+
+        // as many completions as fit behind the command
+        let used = self.command.text().chars().count() + 2;
+        let mut width = (self.termx() as usize).saturating_sub(used);
+        write!(self.screen, "{}", termion::color::Fg(termion::color::LightBlack)).unwrap();
+        for (i, tag) in self.completions.iter().enumerate() {
+            let len = tag.chars().count() + 1;
+            if len > width {
+                break;
+            }
+
+            width -= len;
+            if Some(i) == self.completion {
+                write!(self.screen, " {}{}{}", termion::style::Invert, tag,
+                    termion::style::NoInvert).unwrap();
+            } else {
+                write!(self.screen, " {}", tag).unwrap();
+            }
+        }
+        write!(self.screen, "{}", FG_RESET).unwrap();
+    }
+
+    // Updates the completions for the tag currently typed in the command.
+    // Tagging completes all known tags, untagging only the tags of the
+    // nodes it would affect.
+    fn update_completions(&mut self) {
+        self.completions.clear();
+        self.completion = None;
+
+        let text = self.command.text();
+        let args: Vec<&str> = text.split(|c| c == ',' || c == ' ').collect();
+        if args.len() < 2 {
+            return;
+        }
+
+        let partial = args[args.len() - 1];
+        let typed = &args[1..args.len() - 1];
+        let mut completions: Vec<String> = match args[0] {
+            "t" | "tag" => self.known_tags.iter()
+                .filter(|tag| tag.starts_with(partial))
+                .cloned()
+                .collect(),
+            "ut" | "untag" => {
+                let (ids, _) = self.selection_or_hover();
+                self.nodes.iter()
+                    .filter(|node| ids.contains(&node.id))
+                    .flat_map(|node| node.tags.iter())
+                    .filter(|tag| tag.starts_with(partial))
+                    .cloned()
+                    .collect()
+            }, _ => return,
+        };
+
+        completions.retain(|tag| !typed.contains(&tag.as_str()));
+        completions.sort();
+        completions.dedup();
+        self.completions = completions;
+    }
+
+    // Replaces the last word of the command with the next completion.
+    // Returns false if there is nothing to complete.
+    fn complete(&mut self) -> bool {
+        if self.completions.is_empty() {
+            return false;
+        }
+
+        let i = match self.completion {
+            Some(i) => (i + 1) % self.completions.len(),
+            None => 0,
+        };
+        self.completion = Some(i);
+
+        let text = self.command.text();
+        let start = text.rfind(|c| c == ',' || c == ' ')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let completed = format!("{}{}", &text[..start], self.completions[i]);
+        self.command.set(&completed);
+        true
     }
 
     pub fn exec_cmd(&mut self, args: &[&str], conn: &Connection) {
@@ -597,21 +1167,68 @@ impl<W: Write> SelectScreen<W> {
                 util::remove_tags(conn, &nodes, &args[1..]).unwrap();
                 self.reload_nodes(conn);
             },
-            // TODO: is using 2 commands really intuitive?
-            // maybe rather something like ":a true|false|both"?
+            // '+N'/'-N' change the priority, 'N' sets it
+            "p" | "priority" if args.len() == 2 && !self.nodes.is_empty() => {
+                let relative = args[1].starts_with(|c| c == '+' || c == '-');
+                let value = match args[1].trim_start_matches('+').parse::<i32>() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        self.status = Some(format!("Invalid priority '{}'", args[1]));
+                        return;
+                    }
+                };
+
+                let hover = self.nodes[self.hover].id;
+                let (nodes, _) = self.selection_or_hover();
+                if relative {
+                    util::priority_add(conn, &nodes, value).unwrap();
+                } else {
+                    util::priority_set(conn, &nodes, value).unwrap();
+                }
+                self.reload_nodes(conn);
+                self.set_hover_to_id(hover);
+            },
+            // sets the label color, e.g. ':color red' or ':color none'
+            "c" | "color" if args.len() == 2 && !self.nodes.is_empty() => {
+                let color = match args[1] {
+                    "none" => None,
+                    name if label_color(name).is_some() => Some(name),
+                    name => {
+                        self.status = Some(format!(
+                            "Invalid color '{}', use none or [light]{}",
+                            name, LABEL_COLORS.join("|")));
+                        return;
+                    }
+                };
+
+                let (nodes, _) = self.selection_or_hover();
+                util::set_color(conn, &nodes, color).unwrap();
+                self.reload_nodes(conn);
+            },
+            // sets the archived filter, e.g. ':archived both'
+            "a" | "archived" if args.len() == 2 => {
+                match util::ArchivedFilter::parse(args[1]) {
+                    Some(filter) => self.args.archived = filter,
+                    None => {
+                        self.status = Some(format!("Invalid archived filter '{}'", args[1]));
+                        return;
+                    }
+                }
+                self.reload_nodes(conn);
+            },
             "a" => { // toggle show archived
                 self.args.archived = match self.args.archived {
-                    None => Some(false),
-                    Some(false) => None,
+                    util::ArchivedFilter::All => util::ArchivedFilter::Active,
+                    util::ArchivedFilter::Active => util::ArchivedFilter::All,
                     // don't toggle it in this case, see :A
-                    Some(true) => Some(true),
+                    util::ArchivedFilter::Archived => util::ArchivedFilter::Archived,
                 };
                 self.reload_nodes(conn);
             },
             "A" => { // toggle only show archived
                 self.args.archived = match self.args.archived {
-                    Some(true) => Some(false),
-                    _ => Some(true),
+                    util::ArchivedFilter::Archived => util::ArchivedFilter::Active,
+                    _ => util::ArchivedFilter::Archived,
                 };
                 self.reload_nodes(conn);
             }
@@ -619,6 +1236,40 @@ impl<W: Write> SelectScreen<W> {
         }
     }
 
+    // Executes the commands taking the rest of the line as text,
+    // which must not be split into arguments.
+    // Returns false if the command isn't one of them.
+    fn exec_line_cmd(&mut self, command: &str, conn: &Connection) -> bool {
+        let mut split = command.splitn(2, ' ');
+        let name = split.next().unwrap_or("");
+        let text = split.next().unwrap_or("");
+        let prepend = match name {
+            "append" => false,
+            "prepend" => true,
+            _ => return false,
+        };
+
+        if self.nodes.is_empty() || text.is_empty() {
+            return true;
+        }
+
+        let id = self.nodes[self.hover].id;
+        let res = if prepend {
+            util::prepend(conn, id, text)
+        } else {
+            util::append(conn, id, text)
+        };
+
+        match res {
+            Ok(()) => {
+                self.reload_nodes(conn);
+                self.set_hover_to_id(id);
+            }, Err(err) => self.status = Some(format!("Failed to add line: {}", err)),
+        }
+
+        true
+    }
+
     // TODO: better specific tagging modes (starting just via 't' in normal mode)
     // show context-sensitive suggestions, enter will confirm/use them immediately
     pub fn input_cmd(&mut self, key: Key, conn: &Connection) -> bool {
@@ -634,48 +1285,109 @@ impl<W: Write> SelectScreen<W> {
                 end = true;
                 exec = true;
             },
-            Key::Backspace => {
-                if self.command.pop().is_none() {
-                    end = true;
-                }
+            Key::Backspace if self.command.is_empty() => {
+                end = true;
+            },
+            Key::Char('\t') => change = self.complete(),
+            Key::Up => match self.command_history.prev(self.command.text()) {
+                Some(entry) => self.command.set(entry),
+                None => change = false,
             },
-            Key::Char(c) => {
-                self.command.push(c);
+            Key::Down => match self.command_history.next() {
+                Some(entry) => self.command.set(entry),
+                None => change = false,
             },
-            _ => change = false,
+            key => change = self.command.input(key),
+        }
+
+        // cycling through completions keeps them
+        if change && key != Key::Char('\t') {
+            self.update_completions();
         }
 
         if exec {
             // handle command
-            let mut command = String::new();
-            std::mem::swap(&mut command, &mut self.command);
-            let args: Vec<&str> = command
-                .split(|c| c == ',' || c == ' ')
-                .collect();
-            self.exec_cmd(&args, &conn);
-            self.command = String::new();
+            let command = self.command.take();
+            self.command_history.push(&command);
+            if !self.exec_line_cmd(&command, &conn) {
+                let args: Vec<&str> = command
+                    .split(|c| c == ',' || c == ' ')
+                    .collect();
+                self.exec_cmd(&args, &conn);
+            }
         }
 
         if end {
+            self.completions.clear();
             self.state = State::Normal;
         }
 
         if change || exec || end {
-            self.render();
+            self.render(conn);
         }
 
         true
     }
 }
 
+// Returns the ansi value of the label color with the given name.
+fn label_color(name: &str) -> Option<u8> {
+    let (name, offset) = if name.starts_with("light") {
+        (&name["light".len()..], 8)
+    } else {
+        (name, 0)
+    };
+
+    LABEL_COLORS.iter().position(|c| *c == name).map(|i| i as u8 + offset)
+}
+
+// Returns the path of the history file with the given name.
+// History is only persisted if the config folder exists.
+fn history_path(name: &str) -> Option<PathBuf> {
+    let folder = Config::config_folder();
+    if folder.is_dir() {
+        Some(folder.join(name))
+    } else {
+        None
+    }
+}
+
 // NOTE: probably cleaner implementation using channels...
-pub fn select(conn: &Connection, args: &clap::ArgMatches) -> i32 {
+pub fn select(conn: &Connection, config: &Config, storage: &str,
+        edit_options: &util::EditOptions, args: &clap::ArgMatches) -> i32 {
     let nodes: Vec<SelectNode>;
 
+    // Keys are read from the tty and not stdin. This way stdin can be
+    // used to pass the ids to select from; they have to be read
+    // completely before we start reading keys.
+    let ids = if args.is_present("stdin") {
+        let mut ids = Vec::new();
+        util::read_stdin_ids(args.is_present("null"), |id| ids.push(id));
+        Some(ids)
+    } else {
+        None
+    };
+
     // when scope exits the terminal was restored
     // setup terminal
     {
-        let stdin = io::stdin();
+        let tty = match termion::get_tty() {
+            Ok(tty) => tty,
+            Err(err) => {
+                println!("Failed to open tty: {}", err);
+                return -2;
+            }
+        };
+
+        // the layout depends on it, better not start than guess
+        let termsize = match util::terminal_size() {
+            Ok(size) => size,
+            Err(err) => {
+                println!("Failed to get the terminal size: {}", err);
+                return -2;
+            }
+        };
+
         let raw = match termion::get_tty().and_then(|tty| tty.into_raw_mode()) {
             Ok(r) => r,
             Err(err) => {
@@ -694,41 +1406,27 @@ pub fn select(conn: &Connection, args: &clap::ArgMatches) -> i32 {
             return -3;
         }
 
-        let ms = Arc::new(Mutex::new(SelectScreen::new(&conn, &args, screen)));
-        use std::sync::atomic;
-        let run_size = Arc::new(atomic::AtomicBool::new(true));
+        let signals = match Signals::new(&[SIGWINCH]) {
+            Ok(signals) => signals,
+            Err(err) => {
+                println!("Failed to register resize handler: {}", err);
+                return -3;
+            }
+        };
 
-        let trun_size = run_size.clone();
-        let tms = ms.clone();
+        let ms = Arc::new(Mutex::new(SelectScreen::new(&conn, config, storage,
+            edit_options, &args, ids, termsize, screen)));
 
-        // TODO: use signal again instead
-        // cleanup cleanup process
+        // handles resizes until the signals are closed
+        let tsignals = signals.clone();
+        let tms = ms.clone();
         let sizet = thread::spawn(move || {
-            let mut termsize = util::terminal_size();
-            while trun_size.load(atomic::Ordering::SeqCst) {
-                let ntermsize = util::terminal_size();
-                if ntermsize != termsize {
-                    termsize = ntermsize;
-                    let mut s = tms.lock().unwrap();
-                    s.resized(termsize);
+            for _ in tsignals.forever() {
+                // keep the last size if the new one is unknown
+                if let Ok(size) = util::terminal_size() {
+                    tms.lock().unwrap().resized(size);
                 }
-
-                thread::sleep(Duration::from_millis(50));
             }
-
-            // alternative, more efficient implementation
-            // problem: not sure how to terminate when joining...
-            /*
-            let signals = Signals::new(&[SIGWINCH]).unwrap();
-            for sig in signals.forever() {
-                if sig == SIGWINCH {
-                    // sigsender.send(Message::Resized).unwrap();
-                    let mut s = tms.lock().unwrap();
-                    eprintln!("resizing");
-                    s.resized(util::terminal_size());
-                }
-            }
-            */
         });
 
         // make sure terminal is cleaned up
@@ -743,7 +1441,7 @@ pub fn select(conn: &Connection, args: &clap::ArgMatches) -> i32 {
             screen.screen.flush().unwrap();
         }};
 
-        let keys = stdin.keys();
+        let keys = tty.keys();
         for c in keys {
             let c = c.unwrap();
             let mut s = ms.lock().unwrap();
@@ -753,16 +1451,43 @@ pub fn select(conn: &Connection, args: &clap::ArgMatches) -> i32 {
         }
 
         // we join the thread so that the terminal is converted back
-        run_size.store(false, atomic::Ordering::SeqCst);
+        signals.close();
         sizet.join().unwrap();
         nodes = ms.lock().unwrap().nodes.clone();
     }
 
     // output selected nodes
-    for node in nodes {
-        if node.selected {
-            println!("{}", node.id);
+    let print = args.value_of("print").unwrap_or("id");
+    let delimiter = args.value_of("delimiter").unwrap_or("---");
+    let end = if args.is_present("print0") { "\0" } else { "\n" };
+    let mut first = true;
+    for node in nodes.iter().filter(|node| node.selected) {
+        match print {
+            "summary" => print!("{}{}", node.summary, end),
+            "content" => {
+                let full = match util::fetch_node_full(conn, node.id) {
+                    Ok(full) => full,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return -2;
+                    }
+                };
+
+                if !first && end == "\n" {
+                    println!("{}", delimiter);
+                }
+
+                // the delimiter has to start on its own line but
+                // content with a final newline doesn't need another one
+                if end == "\n" && full.content.ends_with('\n') {
+                    print!("{}", full.content);
+                } else {
+                    print!("{}{}", full.content, end);
+                }
+            },
+            _ => print!("{}{}", node.id, end),
         }
+        first = false;
     }
 
     0
@@ -1,52 +1,25 @@
 use std::io;
 use std::io::prelude::*;
 use std::process;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::error;
 use std::fmt;
+use std::collections::{BTreeMap, HashSet};
 
 use clap::{values_t, value_t};
 use nodes::pattern;
+use nodes::{Config, StorageDefaults};
 
-use rusqlite::{Connection, ToSql};
+use rusqlite::{Connection, OptionalExtension, ToSql};
 use tempfile::NamedTempFile;
+use serde::{Serialize, Deserialize};
+use unicode_width::UnicodeWidthStr;
+use unicode_segmentation::UnicodeSegmentation;
 
-#[derive(PartialEq)]
-pub enum Order {
-    Asc,
-    Desc
-}
-
-impl Order {
-    pub fn name(&self) -> &'static str {
-        match self {
-            Order::Asc => "ASC",
-            Order::Desc => "DESC",
-        }
-    }
-
-    pub fn toggle(&self) -> Order {
-        match self {
-            Order::Asc => Order::Desc,
-            Order::Desc => Order::Asc,
-        }
-    }
-}
-
-pub enum Sort {
-    ID,
-    Priority,
-    Edited,
-}
-
-impl Sort {
-    pub fn name(&self) -> &'static str {
-        match self {
-            Sort::ID => "id",
-            Sort::Priority => "priority",
-            Sort::Edited => "edited",
-        }
-    }
-}
+pub use nodes::query::{Order, Sort, ArchivedFilter, Node, OwnedNode,
+    QueryArgs, in_string};
 
 #[derive(Debug)]
 pub enum Error {
@@ -54,6 +27,10 @@ pub enum Error {
     IO(io::Error), // io operation failed unexpectedly
     InvalidNode(u32), // node with id doesn't exist
     EmptyNode, // create: empty node
+    Aborted, // transaction was aborted, changes were rolled back
+    RecursiveEditor(String), // editor would launch nodes itself
+    InvalidLines(usize, usize), // edit: line range not in node
+    NodeExists(u32), // import: node with id already exists
 }
 
 impl fmt::Display for Error {
@@ -63,6 +40,12 @@ impl fmt::Display for Error {
             Error::IO(err) => write!(f, "IO Error: {}", err),
             Error::InvalidNode(id) => write!(f, "Invalid node id {}", id),
             Error::EmptyNode => write!(f, "Empty Node not created"),
+            Error::Aborted => write!(f, "Aborted, no changes applied"),
+            Error::RecursiveEditor(prog) => write!(f,
+                "Editor '{}' is nodes itself, refusing to launch it", prog),
+            Error::InvalidLines(first, last) => write!(f,
+                "Node has no lines {}:{}", first, last),
+            Error::NodeExists(id) => write!(f, "Node {} already exists", id),
         }
     }
 }
@@ -74,6 +57,10 @@ impl error::Error for Error {
             Error::IO(err) => err.description(),
             Error::InvalidNode(_) => "The given node id was invalid",
             Error::EmptyNode => "Empty Node not created",
+            Error::Aborted => "Aborted, no changes applied",
+            Error::RecursiveEditor(_) => "The editor is nodes itself",
+            Error::InvalidLines(_, _) => "The node doesn't have the given lines",
+            Error::NodeExists(_) => "A node with the given id already exists",
         }
     }
 
@@ -83,6 +70,10 @@ impl error::Error for Error {
             Error::IO(err) => Some(err),
             Error::InvalidNode(_) => None,
             Error::EmptyNode => None,
+            Error::Aborted => None,
+            Error::RecursiveEditor(_) => None,
+            Error::InvalidLines(_, _) => None,
+            Error::NodeExists(_) => None,
         }
     }
 }
@@ -99,38 +90,110 @@ impl From<io::Error> for Error {
     }
 }
 
-/// Trims the given string to the length max_length.
-/// The last three chars will be "..." if the string was longer
-/// than max_length.
-pub fn short_string(lstr: &str, max_length: usize) -> String {
-    let mut too_long = false;
-    let mut s = String::new();
-    let mut append = String::new();
+/// Formats the priority and as many complete tags as fit into width
+/// chars, e.g. "(1)[a][b] [+2]". Tags are never cut off, the number of
+/// hidden tags is appended instead. The priority is always included.
+pub fn pack_tags(priority: i32, tags: &[String], width: usize) -> String {
+    let mut res = format!("({})", priority);
+    let len = res.width();
+    let chunks: Vec<String> = tags.iter().map(|t| format!("[{}]", t)).collect();
+    let total: usize = chunks.iter().map(|c| c.width()).sum();
+    if len + total <= width {
+        res.extend(chunks);
+        return res;
+    }
 
-    // TODO: can probably be done more efficiently?
-    for (i, c) in lstr.chars().enumerate() {
-        if i == max_length {
-            too_long = true;
+    // find the most tags that still fit together with the hidden count
+    let mut shown = 0;
+    let mut used = len;
+    for (i, chunk) in chunks.iter().enumerate() {
+        let clen = chunk.width();
+        let more = format!(" [+{}]", chunks.len() - i - 1).len();
+        if used + clen + more > width {
             break;
-        } else if i >= max_length - 3 {
-            append.push(c);
-        } else {
-            s.push(c);
         }
+
+        used += clen;
+        shown = i + 1;
     }
 
-    s.push_str(if too_long { "..." } else { append.as_str() });
+    let more = format!(" [+{}]", chunks.len() - shown);
+    for chunk in &chunks[..shown] {
+        res.push_str(chunk);
+    }
+
+    if used + more.len() <= width {
+        res.push_str(&more);
+    }
+
+    res
+}
+
+/// Trims the given string to fit into max_width terminal columns.
+/// If it was too wide, it ends with "..." instead, which is included
+/// in the width. Grapheme clusters are never split and wide chars
+/// count as two columns.
+pub fn short_string(lstr: &str, max_width: usize) -> String {
+    if lstr.width() <= max_width {
+        return lstr.to_string();
+    }
+
+    let budget = match max_width.checked_sub(3) {
+        Some(budget) => budget,
+        None => return ".".repeat(max_width),
+    };
+
+    let mut s = String::new();
+    let mut width = 0;
+    for grapheme in lstr.graphemes(true) {
+        width += grapheme.width();
+        if width > budget {
+            break;
+        }
+
+        s.push_str(grapheme);
+    }
+
+    s.push_str("...");
     s
 }
 
+/// Wraps the given line into lines of at most width terminal columns.
+/// Like short_string, grapheme clusters are never split. A grapheme
+/// wider than width gets a line of its own. Empty lines stay empty.
+pub fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut used = 0;
+    for grapheme in line.graphemes(true) {
+        let w = grapheme.width();
+        if used + w > width && !current.is_empty() {
+            lines.push(std::mem::take(&mut current));
+            used = 0;
+        }
+
+        current.push_str(grapheme);
+        used += w;
+    }
+
+    if !current.is_empty() || lines.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
 /// Returns a preview of a node contents.
 /// - node: the nodes contents (only works for text)
 /// - lines: the number of lines the preview should have. Should be >0
 /// - width: the number of characters the preview can have at max
-pub fn node_summary(node: &str, mut lines: usize, width: usize) -> String {
+/// - strip: whether to strip the title line, see title_line
+pub fn node_summary(node: &str, mut lines: usize, width: usize,
+        strip: bool) -> String {
+    let node = if strip { skip_blank_lines(node) } else { node };
     let multiline = lines > 1;
     let mut ret = String::new();
-    for line in node.lines() {
+    for (i, line) in node.lines().enumerate() {
         if lines == 0 {
             if multiline {
                 ret.push_str("[...]\n");
@@ -138,6 +201,7 @@ pub fn node_summary(node: &str, mut lines: usize, width: usize) -> String {
             break;
         }
 
+        let line = if i == 0 { title_line(line, strip) } else { line };
         ret.push_str(&short_string(&line, width));
         if multiline {
             ret.push_str("\n\t");
@@ -149,16 +213,114 @@ pub fn node_summary(node: &str, mut lines: usize, width: usize) -> String {
     ret
 }
 
-/// Returns the current width of the terminal in characters.
-pub fn terminal_size() -> (u16, u16) {
-    match termion::terminal_size() {
-        Ok((x,y)) => (x,y),
-        _ => {
-            // TODO
-            eprintln!("failed to retrieve terminal size");
-            (80, 80) // guess
+// Returns the content starting at the first line that isn't blank.
+fn skip_blank_lines(content: &str) -> &str {
+    let mut rest = content;
+    while let Some(end) = rest.find('\n') {
+        if !rest[..end].trim().is_empty() {
+            break;
         }
+        rest = &rest[end + 1..];
     }
+
+    rest
+}
+
+/// Returns the first line of the content, used as its title.
+/// With strip, leading blank lines are skipped and a markdown heading
+/// ('#') or list ('-', '*') marker is removed, with the surrounding
+/// whitespace.
+pub fn title_line(content: &str, strip: bool) -> &str {
+    let content = if strip { skip_blank_lines(content) } else { content };
+    let line = content.lines().next().unwrap_or("");
+    if !strip {
+        return line;
+    }
+
+    let line = line.trim();
+    let line = if line.starts_with('#') {
+        line.trim_start_matches('#')
+    } else if line.starts_with("- ") || line.starts_with("* ") {
+        &line[1..]
+    } else {
+        line
+    };
+
+    line.trim()
+}
+
+/// Writes the nodes as CSV with a header row.
+/// Columns: id, priority, archived, tags (semicolon-separated) and the
+/// first line of the content as summary. Fields are quoted as needed,
+/// records end with \r\n as RFC 4180 asks for.
+pub fn write_csv<W: Write>(out: W, nodes: &[ExportNode]) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::CRLF)
+        .from_writer(out);
+    writer.write_record(&["id", "priority", "archived", "tags", "summary"])?;
+    for node in nodes {
+        // the summary only has a single line, also for \r\n line endings
+        let summary = node.content.lines().next().unwrap_or("");
+        writer.write_record(&[
+            node.id.to_string(),
+            node.priority.to_string(),
+            node.archived.to_string(),
+            node.tags.join(";"),
+            summary.to_string(),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Formats the node as described by the template.
+/// Placeholders: %i (id), %p (priority), %s (first line of the content),
+/// %t (comma-separated tags), %e (edited), %% for a literal %.
+/// Unknown placeholders are kept as they are.
+pub fn format_node(template: &str, node: &Node) -> String {
+    let mut res = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            res.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('i') => res.push_str(&node.id.to_string()),
+            Some('p') => res.push_str(&node.priority.to_string()),
+            Some('s') => res.push_str(node.content.lines().next().unwrap_or("")),
+            Some('t') => res.push_str(&node.tags.join(",")),
+            Some('e') => res.push_str(node.edited),
+            Some('%') => res.push('%'),
+            Some(c) => {
+                res.push('%');
+                res.push(c);
+            },
+            None => res.push('%'),
+        }
+    }
+
+    res
+}
+
+/// Asks the given yes/no question, no is the default.
+/// Returns whether it was answered with yes.
+pub fn confirm<R: BufRead, W: Write>(mut input: R, output: &mut W,
+        question: &str) -> io::Result<bool> {
+    write!(output, "{} [y/N] ", question)?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Returns the current size (columns, rows) of the terminal.
+pub fn terminal_size() -> io::Result<(u16, u16)> {
+    termion::terminal_size()
 }
 
 /// Applies op to all input node ids.
@@ -173,30 +335,42 @@ pub fn operate_ids_stdin<F: FnMut(u32)>(
         }
         0
     } else {
-        let mut res = 0;
-        let stdin = io::stdin();
-        for rline in stdin.lock().lines() {
-            let line = match rline {
-                Err(err) => {
-                    println!("Failed to read line: {}", err);
-                    res += 1;
-                    continue
-                }, Ok(l) => l,
-            };
-
-            let id = match line.parse::<u32>() {
-                Err(e) => {
-                    println!("Invalid node '{}': {}", line, e);
-                    res += 1;
-                    continue;
-                }, Ok(n) => n,
-            };
+        read_stdin_ids(args.is_present("null"), op)
+    }
+}
 
-            op(id);
-        }
+/// Applies op to all node ids read from stdin, one per line or
+/// separated by NUL bytes if null is set.
+/// Returns the number of invalid lines.
+pub fn read_stdin_ids<F: FnMut(u32)>(null: bool, mut op: F) -> i32 {
+    let delim = if null { b'\0' } else { b'\n' };
+    let mut res = 0;
+    let stdin = io::stdin();
+    for rline in stdin.lock().split(delim) {
+        let line = match rline.and_then(|l| String::from_utf8(l)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))) {
+            Err(err) => {
+                println!("Failed to read line: {}", err);
+                res += 1;
+                continue
+            }, Ok(l) => l,
+        };
+
+        // like BufRead::lines, accept \r\n line endings
+        let line = if null { &line } else { line.trim_end_matches('\r') };
+
+        let id = match line.parse::<u32>() {
+            Err(e) => {
+                info!("Invalid node '{}': {}", line, e);
+                res += 1;
+                continue;
+            }, Ok(n) => n,
+        };
 
-        res
+        op(id);
     }
+
+    res
 }
 
 // Gathers the given nodes ids either via the given argument name
@@ -207,178 +381,362 @@ pub fn gather_nodes(args: &clap::ArgMatches, argname: &str) -> Vec<u32> {
     nodes
 }
 
-pub struct Node<'a> {
-    pub id: u32,
-    pub priority: i32,
-    pub content: &'a str,
-    pub tags: Vec<&'a str>
-}
-
-pub struct ListArgs {
-    pub preorder: Order,
-    pub postorder: Order,
-    pub count: Option<usize>,
-    pub pattern: Option<pattern::CondNode>,
-    pub archived: Option<bool>,
-    pub sort: Option<Sort>,
-}
-
-// default order (reverse = false) is ascending for both
-// preorder: the order of nodes (by id) before limiting/counting
-// postorder: the order of nodes after limiting, i.e. the returned order
-//   different pre-/postorders are only relevent if `count` is given.
-// count: the maximum number of nodes to retrieve. If not given, iterate all
-// pattern: optional pattern; only nodes matching this pattern will be returned
-// archived: if not none, will only retrieve matching nodes
+/// Calls op for all nodes matching the given arguments, in order.
+/// Wraps the library query to log it.
 pub fn iter_nodes<F: FnMut(&Node)>(conn: &Connection,
-        args: &ListArgs, mut op: F) {
-
-    let mut qwhere = String::new();
-    let mut where_add = "WHERE";
-
-    if let Some(archived) = args.archived {
-        qwhere = format!("{} {} (archived = {}) ", qwhere, where_add, archived);
-        where_add = "AND";
-    }
+        args: &QueryArgs, op: F) -> Result<(), Error> {
+    verbose!("Query: {}", nodes::query::query_string(args));
+    Ok(nodes::query::iter_nodes(conn, args, op)?)
+}
 
-    if let Some(pattern) = &args.pattern {
-        let pattern = nodes::pattern::tosql(&pattern);
-        qwhere = format!("{} {} {}", qwhere, where_add, pattern);
-        where_add = "AND";
-    }
+/// Returns the number of nodes matching the given arguments,
+/// ignoring the count limit.
+pub fn count_nodes(conn: &Connection, args: &QueryArgs) -> Result<usize, Error> {
+    Ok(nodes::query::count_nodes(conn, args)?)
+}
 
-    let mut qlimit = String::new();
-    if let Some(count) = args.count {
-        qlimit = format!("LIMIT {}", count);
-    }
+/// Returns all nodes matching the given arguments, in order.
+pub fn query_nodes(conn: &Connection, args: &QueryArgs)
+        -> Result<Vec<OwnedNode>, Error> {
+    verbose!("Query: {}", nodes::query::query_string(args));
+    Ok(nodes::query::query_nodes(conn, args)?)
+}
 
-    let mut preorder = String::new();
-    let mut postorder = String::new();
-    if let Some(sort) = &args.sort {
-        preorder = format!("ORDER BY {sort} {order}",
-            sort = sort.name(),
-            order = args.preorder.name());
-        postorder = format!("ORDER BY {sort} {order}",
-            sort = sort.name(),
-            order = args.postorder.name());
+// Builds the QueryArgs from the command line arguments.
+// The defaults of the given storage are used as base for all values
+// not given on the command line.
+// reverse, reverse_display: the default orders of the command
+pub fn extract_list_args<'a>(args: &'a clap::ArgMatches, config: &Config,
+        storage: &str, mut reverse: bool,
+        mut reverse_display: bool) -> QueryArgs {
+    let none = StorageDefaults::default();
+    let defaults = config.storage_defaults(storage).unwrap_or(&none);
+    if let Some(order) = &defaults.order {
+        // commands that display in the sort order follow it
+        let desc = order == "desc";
+        if reverse_display == reverse {
+            reverse_display = desc;
+        }
+        reverse = desc;
     }
 
-    let mut query = format!("
-        SELECT DISTINCT id, priority, content, GROUP_CONCAT(tag)
-        FROM nodes
-            LEFT JOIN tags ON nodes.id = tags.node
-        {where}
-        GROUP BY id
-        {order}
-        {limit}",
-        where = qwhere,
-        limit = qlimit,
-        order = preorder);
-
-    if args.preorder != args.postorder {
-        query = format!("
-            SELECT *
-            FROM ({query})
-            {order}",
-            query = query,
-            order = postorder);
-    }
-
-    let mut stmt = conn.prepare_cached(&query).unwrap();
-    let mut rows = stmt.query(rusqlite::NO_PARAMS).unwrap();
-    while let Some(row) = rows.next().unwrap() {
-        let tags = row.get_raw(3).as_str().map(|s| s.split(",").collect());
-        let n = Node {
-            id: row.get_unwrap(0),
-            priority: row.get_unwrap(1),
-            content: row.get_raw(2).as_str().unwrap(),
-            tags: tags.unwrap_or(Vec::new())
-        };
-        op(&n);
-    }
-}
-
-pub fn extract_list_args<'a>(args: &'a clap::ArgMatches, mut reverse: bool,
-            mut reverse_display: bool) -> ListArgs {
     reverse ^= args.is_present("reverse");
     reverse_display ^= args.is_present("reverse_display");
 
-    let limit = if args.is_present("num") {
+    // explicitly given values take precedence over the storage
+    // defaults which take precedence over the default values
+    let limit = if args.occurrences_of("num") == 0 && defaults.count.is_some() {
+        defaults.count
+    } else if args.is_present("num") {
         Some(value_t!(args, "num", usize).unwrap_or_else(|e| e.exit()))
     } else {
         None
     };
 
+    // -a and -A are just aliases for the respective --archived values
     let archived = if args.is_present("only_archived") {
-        Some(true)
-    } else if args.is_present("archived") {
-        None
+        ArchivedFilter::Archived
+    } else if args.is_present("all") {
+        ArchivedFilter::All
     } else {
-        Some(false)
+        let name = args.value_of("archived")
+            .or(defaults.archived.as_ref().map(String::as_str));
+        match name {
+            Some(name) => ArchivedFilter::parse(name).unwrap_or_else(|| {
+                eprintln!("Invalid archived filter: {}", name);
+                std::process::exit(1);
+            }),
+            None => ArchivedFilter::Active,
+        }
     };
 
     let pattern = match args.value_of("pattern").map(pattern::parse_condition) {
         Some(Ok(cond)) => Some(cond),
-        Some(Err(_)) => {
-            eprintln!("Invalid pattern");
+        Some(Err(err)) => {
+            eprintln!("Invalid pattern: {}", err);
             None
         }, None => None,
     };
 
+    // --tag filters are added to the pattern, all of them must match
+    let tags = args.values_of("tag").into_iter().flatten()
+        .map(|tag| pattern::CondNode::new(
+            pattern::CondNodeType::Tag(tag.to_string())));
+    let pattern = pattern::all_of(pattern.into_iter().chain(tags).collect());
+
     let sort = match args.value_of("sort") {
-        Some("id") => Sort::ID,
-        Some("priority") => Sort::Priority,
-        Some("edited") => Sort::Edited,
-        Some(s) => {
+        Some(s) => Sort::parse(s).unwrap_or_else(|| {
             eprintln!("Invalid sorting mode: {}", s);
             std::process::exit(0);
-        },
-        None => Sort::ID,
+        }),
+        None => config.default_sort(storage).unwrap_or(Sort::ID),
     };
 
-    ListArgs {
+    let mut list_args = QueryArgs {
         preorder: if reverse { Order::Desc } else { Order::Asc },
         postorder: if reverse_display { Order::Desc } else { Order::Asc },
         pattern: pattern,
         count: limit,
         archived: archived,
         sort: Some(sort),
+        ids: None,
+        content_prefix: None,
+        pinned_first: !args.is_present("no_pin_priority"),
+    };
+
+    // --oldest/--newest shortcuts, just set sort, order and count
+    let oldest = args.is_present("oldest");
+    if oldest || args.is_present("newest") {
+        let argname = if oldest { "oldest" } else { "newest" };
+        let count = value_t!(args, argname, usize).unwrap_or_else(|e| e.exit());
+        let by = args.value_of("by").unwrap_or("edited");
+        list_args.sort = Some(Sort::parse(by).unwrap());
+        list_args.count = Some(count);
+        list_args.preorder = if oldest { Order::Asc } else { Order::Desc };
     }
+
+    list_args
 }
 
-/// Edits the node with the given id
-pub fn edit(conn: &Connection, id: u32) -> Result<(), Error> {
-    // NOTE: maybe this all can be done more efficiently with a memory map?
-    // copy node content into file
-    let mut file = NamedTempFile::new().unwrap();
-    let r = conn.query_row(
-        "SELECT content FROM nodes WHERE id = ?1", &[id],
-        |row| {
-            file.write(&row.get_raw(0).as_str().unwrap().as_bytes()).unwrap();
-            file.seek(io::SeekFrom::Start(0)).unwrap();
-            Ok(())
+// Returns the path of the given program, searching $PATH if it
+// is not a path itself.
+fn find_program(prog: &str) -> Option<PathBuf> {
+    if prog.contains('/') {
+        return Some(PathBuf::from(prog));
+    }
+
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(prog))
+        .find(|path| path.is_file())
+}
+
+/// Makes sure the given editor program doesn't resolve to the running
+/// nodes binary, which would otherwise recurse (e.g. with EDITOR=nodes).
+pub fn check_editor(prog: &str) -> Result<(), Error> {
+    let exe = env::current_exe().and_then(|p| p.canonicalize());
+    let prog_path = find_program(prog).and_then(|p| p.canonicalize().ok());
+    match (exe, prog_path) {
+        (Ok(exe), Some(prog_path)) if exe == prog_path =>
+            Err(Error::RecursiveEditor(prog.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Settings for creating and editing nodes, taken from the config.
+#[derive(Clone)]
+pub struct EditOptions {
+    pub editor: Vec<String>, // program and arguments, never empty
+    pub size_warning: usize, // 0 for no warning
+}
+
+impl EditOptions {
+    pub fn new(config: &Config) -> EditOptions {
+        EditOptions {
+            editor: resolve_editor(config),
+            size_warning: config.size_warning(),
         }
-    );
+    }
+}
+
+/// Returns the editor command to use: programs.editor from the config,
+/// then $VISUAL, then $EDITOR and nvim if none of them is set.
+pub fn resolve_editor(config: &Config) -> Vec<String> {
+    config.editor().cloned()
+        .or_else(|| ["VISUAL", "EDITOR"].iter()
+            .filter_map(|var| env::var(var).ok())
+            .map(|e| e.split_whitespace().map(String::from).collect::<Vec<_>>())
+            .find(|e| !e.is_empty()))
+        .unwrap_or_else(|| vec!("nvim".to_string()))
+}
+
+/// Returns the clipboard command to use: programs.clipboard from the
+/// config or the usual tool for the platform, if it is installed.
+pub fn resolve_clipboard(config: &Config) -> Option<Vec<String>> {
+    if let Some(clipboard) = config.clipboard() {
+        return Some(clipboard.clone());
+    }
 
-    if let Err(e) = r {
-        if e == rusqlite::Error::QueryReturnedNoRows {
-            return Err(Error::InvalidNode(id));
+    let default: &[&str] = if cfg!(target_os = "macos") {
+        &["pbcopy"]
+    } else if env::var_os("WAYLAND_DISPLAY").is_some() {
+        &["wl-copy"]
+    } else {
+        &["xclip", "-selection", "clipboard"]
+    };
+
+    find_program(default[0])?;
+    Some(default.iter().map(|s| s.to_string()).collect())
+}
+
+/// Copies the text to the clipboard using the given clipboard command
+pub fn copy_to_clipboard(clipboard: &[String], text: &str) -> io::Result<()> {
+    let mut child = process::Command::new(&clipboard[0])
+        .args(&clipboard[1..])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(text.as_bytes())?;
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+            format!("'{}' failed with {}", clipboard[0], status)));
+    }
+
+    Ok(())
+}
+
+/// Returns the pager command to use: programs.pager from the config,
+/// then $PAGER and less if neither is set.
+pub fn resolve_pager(config: &Config) -> Vec<String> {
+    config.pager().cloned()
+        .or_else(|| env::var("PAGER").ok()
+            .map(|p| p.split_whitespace().map(String::from).collect::<Vec<_>>())
+            .filter(|p| !p.is_empty()))
+        .unwrap_or_else(|| vec!("less".to_string()))
+}
+
+/// Runs f with a writer for its output. When enabled and stdout is a
+/// terminal that's the input of the pager, otherwise stdout.
+/// Returns the code returned by f. The pager being closed early
+/// is not an error.
+pub fn with_pager<F>(config: &Config, enabled: bool, f: F) -> i32
+        where F: FnOnce(&mut dyn Write) -> io::Result<i32> {
+    let check = |res: io::Result<i32>| match res {
+        Ok(code) => code,
+        Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(err) => {
+            eprintln!("Failed to write output: {}", err);
+            -3
+        }
+    };
+
+    let stdout = io::stdout();
+    if !enabled || !termion::is_tty(&stdout) {
+        let mut stdout = stdout.lock();
+        return check(f(&mut stdout).and_then(|code| {
+            stdout.flush()?;
+            Ok(code)
+        }));
+    }
+
+    let pager = resolve_pager(config);
+    verbose!("Pager: {:?}", pager);
+    let mut cmd = process::Command::new(&pager[0]);
+    cmd.args(&pager[1..]).stdin(process::Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        // quit if everything fits on one screen, keep colors
+        cmd.env("LESS", "FRX");
+    }
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            info!("Failed to start pager '{}': {}", pager[0], err);
+            return check(f(&mut stdout.lock()));
         }
+    };
+
+    let code = {
+        let mut input = io::BufWriter::new(child.stdin.take().unwrap());
+        check(f(&mut input).and_then(|code| {
+            input.flush()?;
+            Ok(code)
+        }))
+    };
+
+    // wait until the user closed the pager
+    if let Err(err) = child.wait() {
+        eprintln!("Failed to wait for pager: {}", err);
+    }
 
-        return Err(e.into());
+    code
+}
+
+// Warns if the content is larger than configured. Huge nodes are
+// usually pasted by accident and make listing and editing slow.
+fn check_size(content: &str, options: &EditOptions) {
+    if options.size_warning != 0 && content.len() > options.size_warning {
+        info!("Warning: node content is large ({} bytes)", content.len());
+    }
+}
+
+// Returns the byte range of the lines start to end (1-based, inclusive)
+// in content, including the trailing newline. end is clamped to the
+// number of lines. Returns None if start is not a line in content.
+fn line_range(content: &str, start: usize, end: usize)
+        -> Option<(usize, usize)> {
+    let mut begin = if start == 1 { Some(0) } else { None };
+    let mut line = 1;
+    for (i, _) in content.match_indices('\n') {
+        line += 1; // line `line` begins at i + 1
+        if line == start {
+            begin = Some(i + 1);
+        } else if line == end + 1 {
+            return begin.map(|b| (b, i + 1));
+        }
     }
 
-    // TODO: use programs from config instead of hardcoding nvim...
+    begin.filter(|b| *b < content.len()).map(|b| (b, content.len()))
+}
+
+/// Edits the node with the given id.
+/// If lines is given, only those lines (1-based, inclusive) are
+/// edited and spliced back into the content afterwards.
+pub fn edit(conn: &Connection, options: &EditOptions, id: u32,
+        lines: Option<(usize, usize)>) -> Result<(), Error> {
+    let r = conn.query_row(
+        "SELECT content FROM nodes WHERE id = ?1", &[id],
+        |row| row.get::<_, String>(0));
+    let old = match r {
+        Ok(content) => content,
+        Err(rusqlite::Error::QueryReturnedNoRows) =>
+            return Err(Error::InvalidNode(id)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let (begin, end) = match lines {
+        Some((first, last)) => line_range(&old, first, last)
+            .ok_or(Error::InvalidLines(first, last))?,
+        None => (0, old.len()),
+    };
+
+    // NOTE: maybe this all can be done more efficiently with a memory map?
+    // copy node content into file
+    let mut file = NamedTempFile::new()?;
+    file.write_all(old[begin..end].as_bytes())?;
+    file.seek(io::SeekFrom::Start(0))?;
+
     // run editor on tmp file
-    let prog = vec!("nvim", &file.path().to_str().unwrap());
+    let prog = &options.editor;
+    check_editor(&prog[0])?;
+    verbose!("Running editor: {:?} {}", prog, file.path().display());
     process::Command::new(&prog[0]).args(prog[1..].iter())
+        .arg(file.path())
         .stdout(termion::get_tty().unwrap())
         .stderr(termion::get_tty().unwrap())
         .status()?;
 
     // write back
-    let mut content = String::new();
-    file.into_file().read_to_string(&mut content)?;
+    let mut edited = String::new();
+    file.into_file().read_to_string(&mut edited)?;
+
+    // make sure the edited lines stay separated from the following ones
+    if end < old.len() && !edited.is_empty() && !edited.ends_with('\n') {
+        edited.push('\n');
+    }
+
+    let content = format!("{}{}{}", &old[..begin], edited, &old[end..]);
+    if content == old {
+        // closing the editor without changes only counts as viewing
+        conn.execute("UPDATE nodes SET viewed = CURRENT_TIMESTAMP WHERE id = ?1",
+            &[&id])?;
+        return Ok(());
+    }
+
+    check_size(&content, options);
 
     // update content, set last seen and edited
     let query = "
@@ -391,15 +749,29 @@ pub fn edit(conn: &Connection, id: u32) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn create(conn: &Connection, gcontent: Option<&str>) -> Result<u32, Error> {
+/// Opens the database at the given path. Creates its folder first,
+/// storage folders are only created once they are used.
+pub fn open_db(path: &Path) -> Result<Connection, Error> {
+    if let Some(folder) = path.parent() {
+        fs::create_dir_all(folder)?;
+    }
+
+    Ok(Connection::open(path)?)
+}
+
+pub fn create(conn: &Connection, options: &EditOptions,
+        gcontent: Option<&str>) -> Result<u32, Error> {
     let mut content = String::new();
     if let Some(fcontent) = gcontent {
         content = fcontent.to_string();
     } else {
-        let file = NamedTempFile::new().unwrap();
-        let path = file.path();
-        let prog = vec!("nvim", &path.to_str().unwrap());
-        process::Command::new(&prog[0]).args(prog[1..].iter()).status()?;
+        let file = NamedTempFile::new()?;
+        let prog = &options.editor;
+        check_editor(&prog[0])?;
+        verbose!("Running editor: {:?} {}", prog, file.path().display());
+        process::Command::new(&prog[0]).args(prog[1..].iter())
+            .arg(file.path())
+            .status()?;
         file.into_file().read_to_string(&mut content).unwrap();
     }
 
@@ -407,13 +779,79 @@ pub fn create(conn: &Connection, gcontent: Option<&str>) -> Result<u32, Error> {
         return Err(Error::EmptyNode);
     }
 
+    check_size(&content, options);
+
+    // created has no default in databases that got it via migration
     let query = "
-        INSERT INTO nodes(content)
-        VALUES (?1)";
+        INSERT INTO nodes(content, created)
+        VALUES (?1, CURRENT_TIMESTAMP)";
     conn.execute(query, &[content])?;
     Ok(conn.last_insert_rowid() as u32)
 }
 
+// Replaces the content of the given node with the result of op,
+// which receives the current content. Sets the edited time.
+fn modify_content<F>(conn: &Connection, id: u32, op: F) -> Result<(), Error>
+        where F: FnOnce(&str) -> String {
+    transaction(conn, || {
+        let content: String = conn.query_row(
+            "SELECT content FROM nodes WHERE id = ?1", &[&id],
+            |row| row.get(0))
+            .optional()?
+            .ok_or(Error::InvalidNode(id))?;
+        let query = "
+            UPDATE nodes
+            SET content = ?1,
+                edited = CURRENT_TIMESTAMP
+            WHERE id = ?2";
+        conn.execute(query, &[&op(&content), &id as &dyn ToSql])?;
+        Ok(())
+    })
+}
+
+/// Appends the given text to the content of the node, on a new line.
+pub fn append(conn: &Connection, id: u32, text: &str) -> Result<(), Error> {
+    modify_content(conn, id, |content| {
+        let mut content = content.to_string();
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(text);
+        content
+    })
+}
+
+/// Inserts the given text at the start of the content of the node,
+/// on its own line.
+pub fn prepend(conn: &Connection, id: u32, text: &str) -> Result<(), Error> {
+    modify_content(conn, id, |content| {
+        let mut text = text.to_string();
+        if !content.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(content);
+        text
+    })
+}
+
+/// Runs op inside a transaction, rolling back all changes if it fails.
+/// Uses a savepoint internally so that calls can be nested.
+pub fn transaction<T, F>(conn: &Connection, op: F) -> Result<T, Error>
+        where F: FnOnce() -> Result<T, Error> {
+    conn.execute_batch("SAVEPOINT nodes_transaction")?;
+    match op() {
+        Ok(res) => {
+            conn.execute_batch("RELEASE nodes_transaction")?;
+            Ok(res)
+        }, Err(err) => {
+            conn.execute_batch("
+                ROLLBACK TO nodes_transaction;
+                RELEASE nodes_transaction")?;
+            Err(err)
+        }
+    }
+}
+
 pub fn set_archived(conn: &Connection, id: u32, set: bool) -> Result<(), Error> {
     let query = "
         UPDATE nodes
@@ -423,21 +861,27 @@ pub fn set_archived(conn: &Connection, id: u32, set: bool) -> Result<(), Error>
     Ok(())
 }
 
-// returns sql `in (ids,...)` string for the given ids
-// must be called with at least one value
-pub fn in_string(ids: &[u32]) -> String {
-    let mut qin = "IN (".to_string();
-    let mut first = true;
-    for id in ids {
-        if !first {
-            qin += ",";
-        }
-        qin += &id.to_string();
-        first = false;
-    }
+/// Pins the given nodes if any of them is not pinned yet, unpins
+/// them otherwise.
+pub fn toggle_pinned(conn: &Connection, ids: &[u32]) -> Result<(), Error> {
+    let query = format!("
+        UPDATE nodes
+        SET pinned = (
+            SELECT COUNT(*) > 0 FROM nodes WHERE NOT pinned AND id {ids})
+        WHERE id {ids}", ids = in_string(ids));
+    conn.execute(&query, rusqlite::NO_PARAMS)?;
+    Ok(())
+}
 
-    qin += ")";
-    qin
+/// Sets the color label of the given nodes, None removes it.
+pub fn set_color(conn: &Connection, ids: &[u32], color: Option<&str>)
+        -> Result<(), Error> {
+    let query = format!("
+        UPDATE nodes
+        SET color = ?1
+        WHERE id {}", in_string(ids));
+    conn.execute(&query, &[&color])?;
+    Ok(())
 }
 
 // TODO: check for invalid ids
@@ -480,48 +924,340 @@ pub fn delete(conn: &Connection, id: u32) -> Result<(), Error> {
     Ok(())
 }
 
+/// Everything stored about a node. Allows to restore it after deletion
+/// and is the format used by export and import.
+#[derive(Serialize, Deserialize)]
+pub struct ExportNode {
+    pub id: u32,
+    pub content: String,
+    pub created: Option<String>,
+    pub edited: String,
+    pub viewed: String,
+    pub archived: bool,
+    pub priority: i32,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub color: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Returns backups of the nodes with the given ids, invalid ids
+/// are ignored.
+pub fn backup_nodes(conn: &Connection, ids: &[u32])
+        -> Result<Vec<ExportNode>, Error> {
+    let mut stmt = conn.prepare(&format!("
+        SELECT id, content, created, edited, viewed, archived, priority,
+            pinned, color
+        FROM nodes
+        WHERE id {}
+        ORDER BY id", in_string(ids)))?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| Ok(ExportNode {
+        id: row.get(0)?,
+        content: row.get(1)?,
+        created: row.get(2)?,
+        edited: row.get(3)?,
+        viewed: row.get(4)?,
+        archived: row.get(5)?,
+        priority: row.get(6)?,
+        pinned: row.get(7)?,
+        color: row.get(8)?,
+        tags: Vec::new(),
+    }))?;
+
+    let mut tagstmt = conn.prepare("
+        SELECT tag FROM tags WHERE node = ? ORDER BY tag")?;
+    let mut nodes = Vec::new();
+    for row in rows {
+        let mut node = row?;
+        let tags = tagstmt.query_map(&[&node.id], |row| row.get(0))?;
+        for tag in tags {
+            node.tags.push(tag?);
+        }
+        nodes.push(node);
+    }
+
+    Ok(nodes)
+}
+
+/// Returns everything stored about the node with the given id.
+pub fn fetch_node_full(conn: &Connection, id: u32) -> Result<ExportNode, Error> {
+    backup_nodes(conn, &[id])?.pop().ok_or(Error::InvalidNode(id))
+}
+
+/// Inserts the given nodes with their tags, all or none of them.
+/// With preserve_ids the original ids are kept, e.g. to restore deleted
+/// nodes, and it fails if one of them is in use. Otherwise new ids
+/// are assigned. Returns the ids of the inserted nodes.
+pub fn import_nodes(conn: &Connection, nodes: &[ExportNode],
+        preserve_ids: bool) -> Result<Vec<u32>, Error> {
+    transaction(conn, || {
+        let mut stmt = conn.prepare("
+            INSERT INTO nodes(id, content, created, edited, viewed,
+                archived, priority, pinned, color)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")?;
+        let mut existsstmt = conn.prepare("
+            SELECT COUNT(*) > 0 FROM nodes WHERE id = ?")?;
+        // connections without foreign keys may have left the tags
+        // of deleted nodes behind
+        let mut tagstmt = conn.prepare("
+            INSERT OR IGNORE INTO tags(node, tag)
+            VALUES (?1, ?2)")?;
+        let mut ids = Vec::new();
+        for node in nodes {
+            // a null id is assigned automatically
+            let id = if preserve_ids { Some(node.id) } else { None };
+            if preserve_ids && existsstmt.query_row(&[&node.id],
+                    |row| row.get::<_, bool>(0))? {
+                return Err(Error::NodeExists(node.id));
+            }
+
+            stmt.execute(&[&id as &dyn ToSql, &node.content,
+                &node.created, &node.edited, &node.viewed,
+                &node.archived, &node.priority, &node.pinned, &node.color])?;
+            let id = conn.last_insert_rowid();
+            for tag in &node.tags {
+                tagstmt.execute(&[&id as &dyn ToSql, tag])?;
+            }
+            ids.push(id as u32);
+        }
+
+        Ok(ids)
+    })
+}
+
+/// Inserts backed up nodes again, e.g. to undo their deletion. They
+/// keep their ids if none of them is in use, otherwise all of them get
+/// new ids. Returns the ids and whether they are the original ones.
+pub fn restore_nodes(conn: &Connection, nodes: &[ExportNode])
+        -> Result<(Vec<u32>, bool), Error> {
+    match import_nodes(conn, nodes, true) {
+        Ok(ids) => Ok((ids, true)),
+        Err(Error::NodeExists(_)) =>
+            import_nodes(conn, nodes, false).map(|ids| (ids, false)),
+        Err(err) => Err(err),
+    }
+}
+
 pub fn add_tags<S: AsRef<str>>(conn: &Connection, ids: &[u32], tags: &[S])
         -> Result<(), Error> {
-    let mut query = "INSERT INTO tags(node, tag) VALUES ".to_string();
-    let mut comma = "";
-    let rtags: Vec<String> = tags.iter()
-        .map(|t| t.as_ref().replace("'", "''"))
-        .collect();
-    for id in ids {
-        for tag in &rtags {
-            query += &format!("{}({}, '{}')", comma, id, tag);
-            comma = ", ";
+    transaction(conn, || {
+        let mut stmt = conn.prepare_cached("
+            INSERT INTO tags(node, tag)
+            VALUES (?1, ?2)")?;
+        for id in ids {
+            for tag in tags {
+                stmt.execute(&[id as &dyn ToSql, &tag.as_ref()])?;
+            }
         }
-    }
 
-    conn.execute(&query, rusqlite::NO_PARAMS)?;
-    Ok(())
+        Ok(())
+    })
 }
 
 pub fn remove_tags<S: AsRef<str>>(conn: &Connection, ids: &[u32], tags: &[S])
         -> Result<(), Error> {
-    let mut query = "DELETE FROM tags WHERE ".to_string();
-    let mut comma = "";
+    let query = format!("
+        DELETE FROM tags
+        WHERE node IN {} AND tag IN {}",
+        placeholders(ids.len()), placeholders(tags.len()));
+    let mut params: Vec<&dyn ToSql> = ids.iter()
+        .map(|id| id as &dyn ToSql)
+        .collect();
+    let tags: Vec<&str> = tags.iter().map(|t| t.as_ref()).collect();
+    params.extend(tags.iter().map(|t| t as &dyn ToSql));
 
-    query += "node IN (";
-    for id in ids {
-        query += &format!("{}{}", comma, id);
-        comma = ", ";
+    conn.execute(&query, &params)?;
+    Ok(())
+}
+
+// returns a sql placeholder list `(?, ?, ...)` with n placeholders
+pub fn placeholders(n: usize) -> String {
+    let mut res = "(".to_string();
+    for i in 0..n {
+        res += if i == 0 { "?" } else { ", ?" };
+    }
+    res + ")"
+}
+
+/// Returns all tags together with the number of nodes using them,
+/// sorted by name.
+pub fn list_tags(conn: &Connection) -> Result<Vec<(String, u32)>, Error> {
+    let mut stmt = conn.prepare("
+        SELECT tag, COUNT(node)
+        FROM tags
+        GROUP BY tag
+        ORDER BY tag")?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut tags = Vec::new();
+    for row in rows {
+        tags.push(row?);
     }
+    Ok(tags)
+}
+
+/// Like list_tags but tags are collapsed to their first depth
+/// dot-separated components, e.g. 'proj.alpha.x' to 'proj.alpha' with
+/// depth 2. The count is the number of nodes with any of those tags.
+pub fn list_tag_prefixes(conn: &Connection, depth: usize)
+        -> Result<Vec<(String, u32)>, Error> {
+    let mut stmt = conn.prepare("SELECT tag, node FROM tags")?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS,
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?)))?;
+    let mut prefixes: BTreeMap<String, HashSet<u32>> = BTreeMap::new();
+    for row in rows {
+        let (tag, node) = row?;
+        let prefix: Vec<&str> = tag.split('.').take(depth).collect();
+        prefixes.entry(prefix.join(".")).or_default().insert(node);
+    }
+
+    Ok(prefixes.into_iter().map(|(p, n)| (p, n.len() as u32)).collect())
+}
 
-    query += ") AND tag IN (";
-    comma = "";
-    let rtags: Vec<String> = tags.iter()
-        .map(|t| t.as_ref().replace("'", "''"))
+/// Retags all nodes carrying one of the source tags with dest and
+/// removes the source tags. Returns the number of affected nodes.
+pub fn merge_tags<S: AsRef<str>>(conn: &Connection, sources: &[S], dest: &str)
+        -> Result<usize, Error> {
+    let sources: Vec<&str> = sources.iter()
+        .map(|s| s.as_ref())
+        .filter(|s| *s != dest)
         .collect();
-    for tag in &rtags {
-        query += &format!("{}'{}'", comma, tag);
-        comma = ", ";
+    if sources.is_empty() {
+        return Ok(0);
     }
-    query += ")";
 
-    conn.execute(&query, rusqlite::NO_PARAMS)?;
-    Ok(())
+    let qin = placeholders(sources.len());
+    let mut params: Vec<&dyn ToSql> = vec!(&dest);
+    params.extend(sources.iter().map(|s| s as &dyn ToSql));
+
+    transaction(conn, || {
+        let count: u32 = conn.query_row(&format!("
+            SELECT COUNT(DISTINCT node)
+            FROM tags
+            WHERE tag IN {}", qin), &params[1..], |row| row.get(0))?;
+        conn.execute(&format!("
+            INSERT OR IGNORE INTO tags(node, tag)
+            SELECT node, ? FROM tags
+            WHERE tag IN {}", qin), &params)?;
+        conn.execute(&format!("
+            DELETE FROM tags
+            WHERE tag IN {}", qin), &params[1..])?;
+        Ok(count as usize)
+    })
+}
+
+/// Renames the tag from to to on all nodes. Nodes that already have
+/// both tags just lose from. Returns the number of affected nodes.
+pub fn rename_tag(conn: &Connection, from: &str, to: &str)
+        -> Result<usize, Error> {
+    if from == to {
+        return Ok(0);
+    }
+
+    transaction(conn, || {
+        // rows that would duplicate an existing (node, to) pair are
+        // skipped by the update and removed afterwards
+        let renamed = conn.execute("
+            UPDATE OR IGNORE tags
+            SET tag = ?2
+            WHERE tag = ?1", &[from, to])?;
+        let removed = conn.execute("
+            DELETE FROM tags
+            WHERE tag = ?1", &[from])?;
+        Ok(renamed + removed)
+    })
+}
+
+/// Removes the tags of nodes that no longer exist, they are left
+/// behind when foreign keys aren't enforced. With min_count, also
+/// removes tags used on fewer nodes. Returns the number of removed rows.
+pub fn prune_tags(conn: &Connection, min_count: Option<u32>)
+        -> Result<usize, Error> {
+    transaction(conn, || {
+        let mut removed = conn.execute("
+            DELETE FROM tags
+            WHERE node NOT IN (SELECT id FROM nodes)", rusqlite::NO_PARAMS)?;
+        if let Some(min) = min_count {
+            removed += conn.execute("
+                DELETE FROM tags
+                WHERE tag IN (
+                    SELECT tag
+                    FROM tags
+                    GROUP BY tag
+                    HAVING COUNT(node) < ?1)", &[min])?;
+        }
+
+        Ok(removed)
+    })
+}
+
+/// Overview over a storage, see collect_stats
+pub struct Stats {
+    pub nodes: u32,
+    pub archived: u32,
+    pub tags: u32, // number of distinct tags
+    pub avg_priority: f64,
+    pub median_priority: f64,
+    // None if there are no nodes
+    pub oldest_edit: Option<String>,
+    pub newest_edit: Option<String>,
+    // summed over the contents of all nodes
+    pub chars: usize,
+    pub words: usize,
+}
+
+pub fn collect_stats(conn: &Connection) -> Result<Stats, Error> {
+    let (nodes, archived, avg_priority, oldest_edit, newest_edit) =
+        conn.query_row("
+            SELECT COUNT(*), COALESCE(SUM(archived), 0),
+                COALESCE(AVG(priority), 0), MIN(edited), MAX(edited)
+            FROM nodes", rusqlite::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?,
+                row.get(3)?, row.get(4)?)))?;
+    let tags = conn.query_row("SELECT COUNT(DISTINCT tag) FROM tags",
+        rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+    let mut stmt = conn.prepare("SELECT priority FROM nodes ORDER BY priority")?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let mut priorities: Vec<i32> = Vec::new();
+    for row in rows {
+        priorities.push(row?);
+    }
+
+    let n = priorities.len();
+    let median_priority = match n {
+        0 => 0.0,
+        n if n % 2 == 0 =>
+            (priorities[n / 2 - 1] as f64 + priorities[n / 2] as f64) / 2.0,
+        n => priorities[n / 2] as f64,
+    };
+
+    let mut stmt = conn.prepare("SELECT content FROM nodes")?;
+    let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+    let (mut chars, mut words) = (0, 0);
+    while let Some(row) = rows.next()? {
+        let (c, w) = content_counts(row.get_raw(0).as_str().unwrap_or(""));
+        chars += c;
+        words += w;
+    }
+
+    Ok(Stats {
+        nodes,
+        archived,
+        tags,
+        avg_priority,
+        median_priority,
+        oldest_edit,
+        newest_edit,
+        chars,
+        words,
+    })
+}
+
+/// Returns the number of chars and whitespace-separated words.
+pub fn content_counts(content: &str) -> (usize, usize) {
+    (content.chars().count(), content.split_whitespace().count())
 }
 
 pub fn priority_add(conn: &Connection, ids: &[u32], offset: i32)
@@ -540,3 +1276,372 @@ pub fn priority_add(conn: &Connection, ids: &[u32], offset: i32)
     conn.execute(&query, rusqlite::NO_PARAMS)?;
     Ok(())
 }
+
+pub fn priority_set(conn: &Connection, ids: &[u32], value: i32)
+        -> Result<(), Error> {
+    let query = format!("UPDATE nodes SET priority = ?1 WHERE id {}",
+        in_string(ids));
+    conn.execute(&query, &[value])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let node = |id, content: &str, tags: &[&str]| ExportNode {
+            id,
+            content: content.to_string(),
+            created: None,
+            edited: "2020-01-01 00:00:00".to_string(),
+            viewed: "2020-01-01 00:00:00".to_string(),
+            archived: id == 2,
+            priority: 1,
+            pinned: false,
+            color: None,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        };
+
+        let nodes = vec![
+            node(1, "plain", &["a", "b"]),
+            node(2, "a, \"quoted\" summary\nsecond line", &[]),
+            node(3, "windows\r\nline", &["x"]),
+        ];
+
+        let mut out = Vec::new();
+        write_csv(&mut out, &nodes).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "id,priority,archived,tags,summary\r\n\
+            1,1,false,a;b,plain\r\n\
+            2,1,true,,\"a, \"\"quoted\"\" summary\"\r\n\
+            3,1,false,x,windows\r\n");
+    }
+
+    #[test]
+    fn node_format() {
+        let node = Node {
+            id: 3,
+            priority: -1,
+            content: "summary line\nsecond line",
+            tags: vec!["a", "b"],
+            edited: "2020-01-02 10:00:00",
+            pinned: false,
+            color: None,
+        };
+
+        assert_eq!(format_node("%i:%p:%s", &node), "3:-1:summary line");
+        assert_eq!(format_node("%i,%t,%e", &node), "3,a,b,2020-01-02 10:00:00");
+        assert_eq!(format_node("100%% %x %", &node), "100% %x %");
+        assert_eq!(format_node("", &node), "");
+    }
+
+    #[test]
+    fn shortening() {
+        assert_eq!(short_string("hello", 5), "hello");
+        assert_eq!(short_string("hello world", 8), "hello...");
+        assert_eq!(short_string("hello", 2), "..");
+        assert_eq!(short_string("", 0), "");
+
+        // wide chars take two columns
+        assert_eq!(short_string("日本語", 6), "日本語");
+        assert_eq!(short_string("日本語です", 8), "日本...");
+        assert_eq!(short_string("日本語です", 7), "日本...");
+
+        // combining marks stay with their base char
+        let s = "cafe\u{301} au lait";
+        assert_eq!(short_string(s, 7), "cafe\u{301}...");
+        assert_eq!(short_string(s, 6), "caf...");
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(wrap_line("hello world", 5), vec!["hello", " worl", "d"]);
+        assert_eq!(wrap_line("", 5), vec![""]);
+        assert_eq!(wrap_line("日本語です", 5), vec!["日本", "語で", "す"]);
+        assert_eq!(wrap_line("日本", 1), vec!["日", "本"]);
+        assert_eq!(wrap_line("cafe\u{301}s", 4), vec!["cafe\u{301}", "s"]);
+    }
+
+    #[test]
+    fn tag_packing() {
+        let tags: Vec<String> = vec!["work".into(), "todo".into(), "x".into()];
+        assert_eq!(pack_tags(1, &tags, 40), "(1)[work][todo][x]");
+        assert_eq!(pack_tags(1, &tags, 18), "(1)[work][todo][x]");
+        assert_eq!(pack_tags(1, &tags, 17), "(1)[work] [+2]");
+        assert_eq!(pack_tags(1, &tags, 14), "(1)[work] [+2]");
+        assert_eq!(pack_tags(1, &tags, 13), "(1) [+3]");
+        assert_eq!(pack_tags(-2, &tags, 5), "(-2)");
+        assert_eq!(pack_tags(0, &[], 1), "(0)");
+    }
+
+    #[test]
+    fn titles() {
+        let content = "\n  \n# Heading #1\nbody\n";
+        assert_eq!(title_line(content, false), "");
+        assert_eq!(title_line(content, true), "Heading #1");
+        assert_eq!(title_line("## a", true), "a");
+        assert_eq!(title_line(" - item ", true), "item");
+        assert_eq!(title_line("* item", true), "item");
+        assert_eq!(title_line("**bold**", true), "**bold**");
+        assert_eq!(title_line("", true), "");
+
+        assert_eq!(node_summary(content, 2, 20, true), "Heading #1\n\tbody\n\t");
+        assert_eq!(node_summary("# a\nb", 1, 20, false), "# a");
+    }
+
+    #[test]
+    fn line_ranges() {
+        let content = "a\nbb\nccc\n";
+        assert_eq!(line_range(content, 1, 1), Some((0, 2)));
+        assert_eq!(line_range(content, 2, 3), Some((2, 9)));
+        assert_eq!(line_range(content, 3, 10), Some((5, 9)));
+        assert_eq!(line_range(content, 4, 4), None);
+        assert_eq!(line_range("a\nb", 2, 2), Some((2, 3)));
+        assert_eq!(line_range("", 1, 1), None);
+    }
+
+    // ids of the nodes returned by iter_nodes for the given args
+    fn list_ids(conn: &Connection, args: &QueryArgs) -> Vec<u32> {
+        let mut ids = Vec::new();
+        iter_nodes(conn, args, |node| ids.push(node.id)).unwrap();
+        ids
+    }
+
+    #[test]
+    fn list_order() {
+        let conn = nodes::db::open_memory();
+        conn.execute_batch("
+            INSERT INTO nodes(content, priority, edited, viewed) VALUES
+                ('a', 2, '2020-01-03', '2020-02-01'),
+                ('b', 0, '2020-01-01', '2020-02-04'),
+                ('c', 3, '2020-01-04', '2020-02-02'),
+                ('d', 1, '2020-01-02', '2020-02-03');
+            INSERT INTO tags(node, tag) VALUES (1, 'x'), (1, 'y');").unwrap();
+
+        let mut args = QueryArgs {
+            preorder: Order::Desc,
+            postorder: Order::Asc,
+            count: Some(2),
+            pattern: None,
+            archived: ArchivedFilter::All,
+            sort: Some(Sort::Priority),
+            ids: None,
+            content_prefix: None,
+            pinned_first: false,
+        };
+
+        // the two with highest priority, shown ascending
+        assert_eq!(list_ids(&conn, &args), vec![1, 3]);
+
+        // sort column not used as node column
+        args.sort = Some(Sort::Edited);
+        assert_eq!(list_ids(&conn, &args), vec![1, 3]);
+
+        args.sort = Some(Sort::Viewed);
+        assert_eq!(list_ids(&conn, &args), vec![4, 2]);
+
+        args.sort = Some(Sort::Edited);
+        args.preorder = Order::Asc;
+        args.postorder = Order::Desc;
+        assert_eq!(list_ids(&conn, &args), vec![4, 2]);
+
+        args.sort = Some(Sort::Viewed);
+        assert_eq!(list_ids(&conn, &args), vec![3, 1]);
+
+        args.sort = Some(Sort::ID);
+        args.count = Some(3);
+        assert_eq!(list_ids(&conn, &args), vec![3, 2, 1]);
+
+        args.postorder = Order::Asc;
+        assert_eq!(list_ids(&conn, &args), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn restore() {
+        let conn = nodes::db::open_memory();
+        conn.execute_batch("
+            INSERT INTO nodes(content, priority, archived, created) VALUES
+                ('a', 2, 1, '2020-01-01'),
+                ('b', 0, 0, '2020-01-02');
+            INSERT INTO tags(node, tag) VALUES (1, 'x'), (1, 'y');").unwrap();
+
+        let backup = backup_nodes(&conn, &[1]).unwrap();
+        assert_eq!(backup.len(), 1);
+        assert_eq!(backup[0].tags, vec!["x", "y"]);
+        assert_eq!(delete_range(&conn, &[1]).unwrap(), 1);
+
+        import_nodes(&conn, &backup, true).unwrap();
+        let (content, priority, archived, created): (String, i32, bool, String) =
+            conn.query_row("
+                SELECT content, priority, archived, created
+                FROM nodes WHERE id = 1", rusqlite::NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap();
+        assert_eq!((content.as_str(), priority, archived, created.as_str()),
+            ("a", 2, true, "2020-01-01"));
+
+        assert_eq!(fetch_node_full(&conn, 1).unwrap().content, "a");
+        match fetch_node_full(&conn, 5) {
+            Err(Error::InvalidNode(5)) => (),
+            _ => panic!("node 5 exists"),
+        }
+
+        // the id is in use now
+        match import_nodes(&conn, &backup, true) {
+            Err(Error::NodeExists(1)) => (),
+            _ => panic!("node 1 restored twice"),
+        }
+
+        // without ids it's just a copy
+        import_nodes(&conn, &backup, false).unwrap();
+        let tags: u32 = conn.query_row("
+            SELECT COUNT(*) FROM tags WHERE node = 3",
+            rusqlite::NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(tags, 2);
+
+        // restoring falls back to new ids when one is taken
+        assert_eq!(restore_nodes(&conn, &backup).unwrap(), (vec![4], false));
+        delete_range(&conn, &[1]).unwrap();
+        assert_eq!(restore_nodes(&conn, &backup).unwrap(), (vec![1], true));
+    }
+
+    #[test]
+    fn stats() {
+        let conn = nodes::db::open_memory();
+        let stats = collect_stats(&conn).unwrap();
+        assert_eq!(stats.nodes, 0);
+        assert_eq!(stats.oldest_edit, None);
+
+        conn.execute_batch("
+            INSERT INTO nodes(content, priority, archived, edited) VALUES
+                ('a', 1, 0, '2020-01-03'),
+                ('b and more', 5, 1, '2020-01-01'),
+                ('c', 0, 0, '2020-01-04'),
+                ('d', 2, 0, '2020-01-02');
+            INSERT INTO tags(node, tag) VALUES (1, 'x'), (2, 'x'), (2, 'y');
+            ").unwrap();
+
+        let stats = collect_stats(&conn).unwrap();
+        assert_eq!((stats.nodes, stats.archived, stats.tags), (4, 1, 2));
+        assert_eq!(stats.avg_priority, 2.0);
+        assert_eq!(stats.median_priority, 1.5);
+        assert_eq!(stats.oldest_edit.as_ref().unwrap(), "2020-01-01");
+        assert_eq!(stats.newest_edit.as_ref().unwrap(), "2020-01-04");
+        assert_eq!((stats.chars, stats.words), (13, 6));
+    }
+
+    #[test]
+    fn tag_merging() {
+        let conn = nodes::db::open_memory();
+        conn.execute_batch("
+            INSERT INTO nodes(content) VALUES ('a'), ('b'), ('c'), ('d');
+            INSERT INTO tags(node, tag) VALUES
+                (1, 'todo'), (2, 'to-do'), (2, 'TODO'), (3, 'todo'),
+                (3, 'to-do'), (4, 'other');
+            ").unwrap();
+
+        // the destination may be among the sources
+        let count = merge_tags(&conn, &["to-do", "TODO", "todo"], "todo").unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(list_tags(&conn).unwrap(), vec![
+            ("other".to_string(), 1), ("todo".to_string(), 3)]);
+        assert_eq!(merge_tags(&conn, &["missing"], "todo").unwrap(), 0);
+    }
+
+    #[test]
+    fn pinning() {
+        let conn = nodes::db::open_memory();
+        conn.execute_batch("
+            INSERT INTO nodes(content, created, pinned) VALUES
+                ('a', CURRENT_TIMESTAMP, 0),
+                ('b', CURRENT_TIMESTAMP, 1),
+                ('c', CURRENT_TIMESTAMP, 0);").unwrap();
+        let pinned = |conn: &Connection| -> Vec<u32> {
+            let mut stmt = conn.prepare(
+                "SELECT id FROM nodes WHERE pinned ORDER BY id").unwrap();
+            let ids = stmt.query_map(rusqlite::NO_PARAMS, |row| row.get(0))
+                .unwrap();
+            ids.map(|id| id.unwrap()).collect()
+        };
+
+        // pins all if one isn't pinned yet
+        toggle_pinned(&conn, &[1, 2]).unwrap();
+        assert_eq!(pinned(&conn), vec![1, 2]);
+        toggle_pinned(&conn, &[1, 2]).unwrap();
+        assert_eq!(pinned(&conn), Vec::<u32>::new());
+        toggle_pinned(&conn, &[3]).unwrap();
+        assert_eq!(pinned(&conn), vec![3]);
+    }
+
+    #[test]
+    fn colors() {
+        let conn = nodes::db::open_memory();
+        conn.execute_batch("
+            INSERT INTO nodes(content, created) VALUES
+                ('a', CURRENT_TIMESTAMP),
+                ('b', CURRENT_TIMESTAMP);").unwrap();
+
+        set_color(&conn, &[1, 2], Some("red")).unwrap();
+        set_color(&conn, &[2], None).unwrap();
+        let args = QueryArgs {
+            preorder: Order::Asc,
+            postorder: Order::Asc,
+            count: None,
+            pattern: None,
+            archived: ArchivedFilter::All,
+            sort: Some(Sort::ID),
+            ids: None,
+            content_prefix: None,
+            pinned_first: false,
+        };
+        let colors: Vec<_> = query_nodes(&conn, &args).unwrap().into_iter()
+            .map(|node| node.color)
+            .collect();
+        assert_eq!(colors, vec![Some("red".to_string()), None]);
+
+        // kept in backups
+        let backup = backup_nodes(&conn, &[1]).unwrap();
+        assert_eq!(backup[0].color.as_ref().map(String::as_str), Some("red"));
+    }
+
+    #[test]
+    fn tag_pruning() {
+        let conn = nodes::db::open_memory();
+        // orphaned tags can only exist without enforced foreign keys
+        conn.execute_batch("
+            PRAGMA foreign_keys = OFF;
+            INSERT INTO nodes(content) VALUES ('a'), ('b');
+            INSERT INTO tags(node, tag) VALUES
+                (1, 'x'), (2, 'x'), (1, 'typo'), (3, 'x'), (4, 'gone');
+            ").unwrap();
+
+        assert_eq!(prune_tags(&conn, None).unwrap(), 2);
+        assert_eq!(list_tags(&conn).unwrap().len(), 2);
+        assert_eq!(prune_tags(&conn, Some(2)).unwrap(), 1);
+        assert_eq!(list_tags(&conn).unwrap(), vec![("x".to_string(), 2)]);
+        assert_eq!(prune_tags(&conn, Some(2)).unwrap(), 0);
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(content_counts(""), (0, 0));
+        assert_eq!(content_counts("  \n\t"), (4, 0));
+        assert_eq!(content_counts("one two\nthree"), (13, 3));
+        assert_eq!(content_counts("größe über 日本語"), (14, 3));
+        assert_eq!(content_counts("e\u{301}"), (2, 1));
+    }
+
+    #[test]
+    fn confirmation() {
+        let mut output = Vec::new();
+        assert!(confirm(&b"y\n"[..], &mut output, "Delete?").unwrap());
+        assert_eq!(output, b"Delete? [y/N] ");
+
+        assert!(confirm(&b" YES \n"[..], &mut Vec::new(), "").unwrap());
+        assert!(!confirm(&b"\n"[..], &mut Vec::new(), "").unwrap());
+        assert!(!confirm(&b"yep\n"[..], &mut Vec::new(), "").unwrap());
+        assert!(!confirm(&b""[..], &mut Vec::new(), "").unwrap());
+    }
+}
@@ -1,9 +1,7 @@
 PRAGMA encoding = "UTF-8";
 
-DROP TABLE IF EXISTS nodes;
-DROP TABLE IF EXISTS tags;
-
-CREATE TABLE nodes (
+-- Run on every start, must never touch existing data.
+CREATE TABLE IF NOT EXISTS nodes (
 	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
 	content TEXT NOT NULL,
 	-- mimetype TEXT NOT NULL, -- strictly follow mime standard
@@ -11,10 +9,12 @@ CREATE TABLE nodes (
 	edited DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, -- last edit date (edit command invoked)
 	viewed DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, -- last date viewed (edit/show command invoked)
 	archived BOOLEAN NOT NULL DEFAULT false,
-	priority INTEGER NOT NULL DEFAULT 0
+	priority INTEGER NOT NULL DEFAULT 0,
+	pinned BOOLEAN NOT NULL DEFAULT false, -- listed before all other nodes
+	color TEXT -- name of the label color in lists, NULL for none
 );
 
-CREATE TABLE tags (
+CREATE TABLE IF NOT EXISTS tags (
 	node INTEGER NOT NULL,
 	tag text NOT NULL,
 	PRIMARY KEY(node, tag),
@@ -1,10 +1,23 @@
 use std::io;
 use std::fs;
+use std::fmt;
+use std::error;
 
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::env;
+use std::path::{Component, Path, PathBuf};
 use std::collections::HashMap;
+use crate::query::Sort;
+
+// content size in bytes above which a warning is shown by default
+const DEFAULT_SIZE_WARNING: usize = 1024 * 1024;
+
+// share of the node list width used for the tags column by default
+const DEFAULT_TAG_COLUMN_RATIO: f64 = 0.3;
+
+// the tags column is only shown in node lists wider than this by default
+const DEFAULT_TAG_COLUMN_MIN_WIDTH: usize = 80;
 
 pub struct Config {
     value: Option<toml::Value>,
@@ -14,7 +27,20 @@ pub struct Config {
 
 pub struct StorageConfig {
     default: String,
+    synchronous: Option<String>, // normalized sqlite synchronous mode
     storages: HashMap<String, PathBuf>,
+    defaults: HashMap<String, StorageDefaults>,
+}
+
+/// Default listing flags for a storage, from `[storage.<name>.defaults]`.
+/// Values are only checked for their type here, the command line
+/// interface interprets them.
+#[derive(Default, Clone)]
+pub struct StorageDefaults {
+    pub sort: Option<String>,
+    pub order: Option<String>, // "asc" or "desc"
+    pub count: Option<usize>,
+    pub archived: Option<String>,
 }
 
 #[derive(Debug)]
@@ -22,16 +48,55 @@ pub enum ConfigError {
     Read(io::Error),
     Parse(toml::de::Error),
     InvalidStorage(String),
-    NoStorage,
     NoStorages,
     NoDefaultStorage,
     InvalidPrograms,
-    InvalidDefaultStorage
+    InvalidDefaultStorage,
+    StoragePathMissing(String, PathBuf), // name, path
+    DuplicateStoragePath(String, String, PathBuf), // both names, path
+    DuplicateStorageName(String, String), // equal ignoring case
 }
 
-// TODO: how is config usually handled? when e.g. config file does only
-// set programs but doesn't include any storages should be still use
-// default storages? or distribute (and install) default config file?
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(err) => write!(f, "Failed to read the config: {}", err),
+            // includes the line and column
+            ConfigError::Parse(err) => write!(f, "Invalid TOML: {}", err),
+            ConfigError::InvalidStorage(msg) => write!(f,
+                "Invalid [storage] table: {}", msg),
+            ConfigError::NoStorages => write!(f,
+                "The [storage] table has no storages"),
+            ConfigError::NoDefaultStorage => write!(f,
+                "There are multiple storages but no storage.default"),
+            ConfigError::InvalidPrograms => write!(f,
+                "Invalid [programs] table, its values must be lists of strings"),
+            ConfigError::InvalidDefaultStorage => write!(f,
+                "storage.default must be the name of a configured storage"),
+            ConfigError::StoragePathMissing(name, path) => write!(f,
+                "The folder {} of storage '{}' is a file or lies inside one",
+                path.display(), name),
+            ConfigError::DuplicateStoragePath(a, b, path) => write!(f,
+                "Storages '{}' and '{}' both use the folder {}",
+                a, b, path.display()),
+            ConfigError::DuplicateStorageName(a, b) => write!(f,
+                "Storage names '{}' and '{}' only differ in case", a, b),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Read(err) => Some(err),
+            ConfigError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Config files don't have to declare storages, the default storage
+// is used then, as without a config file.
 impl Config {
     /// Load the configuration from the default location.
     /// Will return the default configuration if the file in
@@ -61,9 +126,9 @@ impl Config {
                 map(Config::parse_storage_config) {
             Some(Ok(s)) => s,
             Some(Err(e)) => return Err(e),
-            None => return Err(ConfigError::NoStorage),
+            None => Config::default_storage_config(),
         };
-
+        Config::validate_storage_paths(&storage)?;
 
         // programs
         let programs = match config.get("programs") {
@@ -96,11 +161,124 @@ impl Config {
         self.storage.storages.get(name)
     }
 
+    /// Returns the names and paths of all configured storages,
+    /// in no particular order.
+    pub fn storages(&self) -> impl Iterator<Item = (&str, &PathBuf)> {
+        self.storage.storages.iter().map(|(name, path)| (name.as_str(), path))
+    }
+
     /// Returns the path of the default storage.
     pub fn default_storage_folder(&self) -> &PathBuf {
         self.storage_folder(&self.storage.default).unwrap()
     }
 
+    /// Returns the name of the default storage.
+    pub fn default_storage(&self) -> &str {
+        &self.storage.default
+    }
+
+    /// Returns the sqlite synchronous mode (off, normal, full or extra)
+    /// configured via `synchronous` in the `[storage]` table, if set.
+    pub fn synchronous_mode(&self) -> Option<&str> {
+        self.storage.synchronous.as_ref().map(|s| s.as_str())
+    }
+
+    /// Returns the configured default flags of the storage with the
+    /// given name, if there are any.
+    pub fn storage_defaults(&self, name: &str) -> Option<&StorageDefaults> {
+        self.storage.defaults.get(name)
+    }
+
+    /// Returns the sort used when listing the nodes of the storage with
+    /// the given name without an explicit sort. Configured via
+    /// `default_sort` in the `[storage.<name>]` table or `sort` in
+    /// its defaults.
+    pub fn default_sort(&self, name: &str) -> Option<Sort> {
+        self.storage_defaults(name)
+            .and_then(|d| d.sort.as_ref())
+            .and_then(|s| Sort::parse(s))
+    }
+
+    /// Returns the command used to render markdown, if configured.
+    /// The content will be passed on its stdin.
+    pub fn markdown_renderer(&self) -> Option<&Vec<String>> {
+        self.programs.get("markdown").filter(|p| !p.is_empty())
+    }
+
+    /// Returns the content size in bytes above which creating or
+    /// editing a node prints a warning. Configured via `size_warning`,
+    /// 0 disables the warning.
+    pub fn size_warning(&self) -> usize {
+        self.value.as_ref()
+            .and_then(|v| v.get("size_warning"))
+            .and_then(|v| v.as_integer())
+            .map(|s| s.max(0) as usize)
+            .unwrap_or(DEFAULT_SIZE_WARNING)
+    }
+
+    /// Returns the configured editor command, if any.
+    /// The file to edit is appended as last argument.
+    pub fn editor(&self) -> Option<&Vec<String>> {
+        self.programs.get("editor").filter(|p| !p.is_empty())
+    }
+
+    /// Returns the pager command from `programs.pager`, if set.
+    pub fn pager(&self) -> Option<&Vec<String>> {
+        self.programs.get("pager").filter(|p| !p.is_empty())
+    }
+
+    /// Returns the clipboard command from `programs.clipboard`, if set.
+    /// It gets the text to copy on stdin.
+    pub fn clipboard(&self) -> Option<&Vec<String>> {
+        self.programs.get("clipboard").filter(|p| !p.is_empty())
+    }
+
+    /// Returns whether the select screen shows a preview of the
+    /// hovered node, configured via `preview` in the `[select]` table.
+    pub fn select_preview(&self) -> bool {
+        self.value.as_ref()
+            .and_then(|v| v.get("select"))
+            .and_then(|v| v.get("preview"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Returns the share of the node list width used for the tags
+    /// column, configured via `tag_column_ratio` in the `[display]` table.
+    /// Clamped to [0.0, 0.9] so the summary always keeps some space.
+    pub fn tag_column_ratio(&self) -> f64 {
+        self.value.as_ref()
+            .and_then(|v| v.get("display"))
+            .and_then(|v| v.get("tag_column_ratio"))
+            .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)))
+            .filter(|r| !r.is_nan())
+            .map(|r| r.max(0.0).min(0.9))
+            .unwrap_or(DEFAULT_TAG_COLUMN_RATIO)
+    }
+
+    /// Returns the node list width from which on the tags column
+    /// is shown, configured via `tag_column_min_width` in the
+    /// `[display]` table.
+    pub fn tag_column_min_width(&self) -> usize {
+        self.value.as_ref()
+            .and_then(|v| v.get("display"))
+            .and_then(|v| v.get("tag_column_min_width"))
+            .and_then(|v| v.as_integer())
+            .map(|w| w.max(0) as usize)
+            .unwrap_or(DEFAULT_TAG_COLUMN_MIN_WIDTH)
+    }
+
+    /// Returns whether markdown heading and list markers are stripped
+    /// from node summaries, configured via `strip_heading_markers` in
+    /// the `[display]` table. Leading blank lines are skipped as well then.
+    pub fn strip_heading_markers(&self) -> bool {
+        self.value.as_ref()
+            .and_then(|v| v.get("display"))
+            .and_then(|v| v.get("strip_heading_markers"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
+
     /// Returns the parsed config file as value
     pub fn value(&self) -> &Option<toml::Value> {
         &self.value
@@ -121,6 +299,21 @@ impl Config {
             _ => return Err(ConfigError::InvalidDefaultStorage),
         };
 
+        // not a storage either, so no storage can be named synchronous.
+        // Values that aren't a mode are likely meant as such a storage
+        const MODES: [&str; 4] = ["off", "normal", "full", "extra"];
+        let synchronous = match storage.remove("synchronous") {
+            Some(Value::String(s)) if MODES.contains(&s.to_lowercase().as_str()) =>
+                Some(s.to_lowercase()),
+            Some(Value::Integer(i)) if i >= 0 && (i as usize) < MODES.len() =>
+                Some(MODES[i as usize].to_string()),
+            Some(_) => return Err(ConfigError::InvalidStorage(
+                "synchronous is the sqlite synchronous mode and must be one of \
+                off, normal, full, extra or 0-3. Storages can't be named \
+                'synchronous'".into())),
+            None => None,
+        };
+
         if storage.len() == 0 {
             return Err(ConfigError::NoStorages);
         } else if default.is_none() && storage.len() != 1 {
@@ -134,11 +327,65 @@ impl Config {
             storage.remove("default").unwrap();
         }
 
-        let paths: HashMap<String, PathBuf> = match storage_val.clone().try_into() {
-            Ok(p) => p,
-            Err(err) => return Err(ConfigError::InvalidStorage(
-                format!("Could not convert to hashmap: {}", err)))
-        };
+        // storages are either given as `name = "path"` or as table
+        // with a path and optional defaults
+        let mut paths = HashMap::new();
+        let mut defaults = HashMap::new();
+        for (name, value) in storage.iter() {
+            let path = match value {
+                Value::String(path) => path,
+                Value::Table(table) => {
+                    let mut d = match table.get("defaults") {
+                        Some(d) => Config::parse_storage_defaults(name, d)?,
+                        None => StorageDefaults::default(),
+                    };
+
+                    // shortcut for defaults.sort
+                    match table.get("default_sort") {
+                        Some(_) if d.sort.is_some() =>
+                            return Err(ConfigError::InvalidStorage(format!(
+                                "Storage '{}' sets both default_sort and \
+                                defaults.sort, use only one", name))),
+                        Some(Value::String(s)) if Sort::parse(s).is_some() =>
+                            d.sort = Some(s.clone()),
+                        Some(_) => return Err(ConfigError::InvalidStorage(format!(
+                            "Invalid default_sort of storage '{}'", name))),
+                        None => (),
+                    }
+
+                    defaults.insert(name.clone(), d);
+
+                    match table.get("path") {
+                        Some(Value::String(path)) => path,
+                        _ => return Err(ConfigError::InvalidStorage(
+                            format!("Storage '{}' has no valid path", name))),
+                    }
+                }, _ => return Err(ConfigError::InvalidStorage(
+                    format!("Storage '{}' is neither path nor table", name))),
+            };
+
+            paths.insert(name.clone(), PathBuf::from(path));
+        }
+
+        // storages sharing a folder would share their database.
+        // Names that only differ in case are easily confused.
+        let mut names: Vec<&String> = paths.keys().collect();
+        names.sort();
+        let mut lowercase: HashMap<String, &String> = HashMap::new();
+        let mut folders: HashMap<PathBuf, &String> = HashMap::new();
+        for name in names {
+            if let Some(other) = lowercase.insert(name.to_lowercase(), name) {
+                return Err(ConfigError::DuplicateStorageName(
+                    other.clone(), name.clone()));
+            }
+
+            let path = &paths[name];
+            let folder = Config::normalize_path(path);
+            if let Some(other) = folders.insert(folder, name) {
+                return Err(ConfigError::DuplicateStoragePath(
+                    other.clone(), name.clone(), path.clone()));
+            }
+        }
 
         let default = default.unwrap();
         if !paths.contains_key(&default) {
@@ -147,30 +394,105 @@ impl Config {
 
         Ok(StorageConfig {
             default: default.clone(),
+            synchronous,
             storages: paths,
+            defaults,
         })
     }
 
-    fn default_config() -> Config {
-        let mut storages = HashMap::new();
+    // Makes the path absolute and resolves it component by component.
+    // As long as the path exists, symlinks are resolved before a ".."
+    // is applied. The rest is normalized lexically, so that e.g. "x",
+    // "x/" and "./x" are the same folder even before it was created.
+    fn normalize_path(path: &Path) -> PathBuf {
+        let path = match env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => path.to_path_buf(),
+        };
+
+        let mut res = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => continue,
+                Component::ParentDir if !res.exists() => {
+                    res.pop();
+                    continue;
+                },
+                c => res.push(c.as_os_str()),
+            }
+
+            if let Ok(resolved) = fs::canonicalize(&res) {
+                res = resolved;
+            }
+        }
+
+        res
+    }
 
-        // we make sure that the default storage exists
-        // when running nodes for the first time this assures that
-        // it can already be used
-        let storage = Config::default_storage_path();
-        if !storage.is_dir() {
-            fs::create_dir_all(&storage)
-                .expect("Failed to create default storage path");
+    // Checks that the folders of all storages exist or lie inside an
+    // existing folder. Permissions are not checked and nothing is
+    // created here, both happen once a storage is opened.
+    fn validate_storage_paths(storage: &StorageConfig) -> Result<(), ConfigError> {
+        for (name, path) in &storage.storages {
+            let valid = match path.ancestors().find(|p| p.exists()) {
+                Some(existing) if existing == path => path.is_dir(),
+                Some(existing) => existing.is_dir(),
+                // relative path of which nothing exists yet
+                None => true,
+            };
+
+            if !valid {
+                return Err(ConfigError::StoragePathMissing(
+                    name.clone(), path.clone()));
+            }
         }
 
-        storages.insert("default".to_string(), storage);
+        Ok(())
+    }
+
+    fn parse_storage_defaults(name: &str, value: &toml::Value)
+            -> Result<StorageDefaults, ConfigError> {
+        use toml::value::Value;
+        let invalid = |key: &str| ConfigError::InvalidStorage(
+            format!("Invalid value for defaults.{} of storage '{}'", key, name));
+        let table = value.as_table().ok_or_else(|| invalid("*"))?;
+
+        let mut defaults = StorageDefaults::default();
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("sort", Value::String(s)) if Sort::parse(s).is_some() =>
+                    defaults.sort = Some(s.clone()),
+                ("archived", Value::String(s)) =>
+                    defaults.archived = Some(s.clone()),
+                ("order", Value::String(s)) if s == "asc" || s == "desc" =>
+                    defaults.order = Some(s.clone()),
+                ("count", Value::Integer(c)) if *c >= 0 =>
+                    defaults.count = Some(*c as usize),
+                (key, _) => return Err(invalid(key)),
+            }
+        }
+
+        Ok(defaults)
+    }
+
+    fn default_config() -> Config {
         Config {
             value: None,
             programs: HashMap::new(),
-            storage: StorageConfig {
-                default: "default".to_string(),
-                storages,
-            }
+            storage: Config::default_storage_config(),
+        }
+    }
+
+    fn default_storage_config() -> StorageConfig {
+        let mut storages = HashMap::new();
+
+        // the folder is created once the storage is first used
+        storages.insert("default".to_string(), Config::default_storage_path());
+        StorageConfig {
+            default: "default".to_string(),
+            synchronous: None,
+            storages,
+            defaults: HashMap::new(),
         }
     }
 
@@ -0,0 +1,265 @@
+use rusqlite::{Connection, NO_PARAMS};
+
+const SCHEMA: &str = include_str!("../schema.sql");
+
+/// Version of the schema in schema.sql. Stored in the database via the
+/// user_version pragma, databases with an older version are upgraded
+/// by migrate.
+pub const CURRENT_SCHEMA_VERSION: u32 = 5;
+
+// Migration steps, the step at index i upgrades a database from
+// version i to version i + 1.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+    // 1: databases from before versioning, the tables already match
+    // so they are just stamped
+    |_| Ok(()),
+    // 2: old databases may not have the created column yet. Columns
+    // can't be added with a non-constant default, so it's nullable
+    // there and set explicitly on creation.
+    |conn| {
+        if has_column(conn, "nodes", "created")? {
+            return Ok(());
+        }
+
+        conn.execute_batch("
+            ALTER TABLE nodes ADD COLUMN created DATETIME;
+            UPDATE nodes SET created = edited;")
+    },
+    // 3: foreign keys are enforced from now on. Tags of deleted nodes
+    // were left behind before, and tables without the constraint
+    // are recreated with it.
+    |conn| {
+        conn.execute_batch("
+            DELETE FROM tags
+            WHERE node NOT IN (SELECT id FROM nodes);")?;
+        if has_foreign_key(conn, "tags", "nodes")? {
+            return Ok(());
+        }
+
+        conn.execute_batch("
+            CREATE TABLE tags_new (
+                node INTEGER NOT NULL,
+                tag text NOT NULL,
+                PRIMARY KEY(node, tag),
+                CONSTRAINT fk_node
+                    FOREIGN KEY (node)
+                    REFERENCES nodes(id)
+                    ON DELETE CASCADE
+            );
+            INSERT INTO tags_new(node, tag) SELECT node, tag FROM tags;
+            DROP TABLE tags;
+            ALTER TABLE tags_new RENAME TO tags;")
+    },
+    // 4: pinned nodes
+    |conn| {
+        if has_column(conn, "nodes", "pinned")? {
+            return Ok(());
+        }
+
+        conn.execute_batch("
+            ALTER TABLE nodes ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT false;")
+    },
+    // 5: color labels
+    |conn| {
+        if has_column(conn, "nodes", "color")? {
+            return Ok(());
+        }
+
+        conn.execute_batch("ALTER TABLE nodes ADD COLUMN color TEXT;")
+    },
+];
+
+fn has_column(conn: &Connection, table: &str, column: &str)
+        -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        if row.get::<_, String>(1)? == column {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn has_foreign_key(conn: &Connection, table: &str, references: &str)
+        -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA foreign_key_list({})", table))?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        if row.get::<_, String>(2)? == references {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Enables enforcement of the foreign keys, e.g. so that the tags
+/// of deleted nodes are deleted as well. Sqlite has it disabled by
+/// default and it has to be enabled for every connection.
+pub fn enable_foreign_keys(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("PRAGMA foreign_keys = ON")
+}
+
+/// Creates the nodes database schema on the given connection.
+/// Tables that already exist are left untouched, so this can be run
+/// on every start. New databases are stamped with the current schema
+/// version, existing ones have to be upgraded via migrate.
+pub fn init_schema(conn: &Connection) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row("
+        SELECT COUNT(*) > 0
+        FROM sqlite_master
+        WHERE type = 'table' AND name = 'nodes'",
+        NO_PARAMS, |row| row.get(0))?;
+    conn.execute_batch(SCHEMA)?;
+    if !exists {
+        set_schema_version(conn, CURRENT_SCHEMA_VERSION)?;
+    }
+
+    Ok(())
+}
+
+/// Opens a new in-memory database with the current schema.
+/// Everything stored in it is lost when the connection is closed.
+pub fn open_memory() -> Connection {
+    // can only fail when sqlite runs out of memory
+    let conn = Connection::open_in_memory()
+        .expect("Failed to open in-memory database");
+    init_schema(&conn).expect("Failed to create in-memory schema");
+    enable_foreign_keys(&conn).expect("Failed to enable foreign keys");
+    conn
+}
+
+/// Returns the schema version stored in the database, None if
+/// there is none yet.
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<Option<u32>> {
+    let version: u32 = conn.query_row("PRAGMA user_version", NO_PARAMS,
+        |row| row.get(0))?;
+    Ok(if version == 0 { None } else { Some(version) })
+}
+
+fn set_schema_version(conn: &Connection, version: u32) -> rusqlite::Result<()> {
+    // pragmas can't be parameterized
+    conn.execute_batch(&format!("PRAGMA user_version = {}", version))
+}
+
+/// Applies the migration steps needed to bring the database to
+/// CURRENT_SCHEMA_VERSION. Each step is applied in its own transaction.
+/// Databases with a newer version than known are not touched.
+pub fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version = schema_version(conn)?.unwrap_or(0) as usize;
+    for (i, step) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch("BEGIN")?;
+        let res = step(conn)
+            .and_then(|_| set_schema_version(conn, (i + 1) as u32));
+        match res {
+            Ok(()) => conn.execute_batch("COMMIT")?,
+            Err(err) => {
+                conn.execute_batch("ROLLBACK")?;
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory() {
+        let conn = open_memory();
+        assert_eq!(schema_version(&conn).unwrap(), Some(CURRENT_SCHEMA_VERSION));
+        conn.execute_batch("INSERT INTO nodes(content, created) \
+            VALUES ('a', CURRENT_TIMESTAMP)").unwrap();
+    }
+
+    #[test]
+    fn versions() {
+        // new databases get the current version
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), Some(CURRENT_SCHEMA_VERSION));
+
+        // unversioned databases are adopted
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn.execute_batch("INSERT INTO nodes(content) VALUES ('a')").unwrap();
+        init_schema(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), None);
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), Some(CURRENT_SCHEMA_VERSION));
+
+        let count: u32 = conn.query_row("SELECT COUNT(*) FROM nodes",
+            NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn created_backfill() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE nodes (
+                id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                content TEXT NOT NULL,
+                edited DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                viewed DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                archived BOOLEAN NOT NULL DEFAULT false,
+                priority INTEGER NOT NULL DEFAULT 0
+            );
+            INSERT INTO nodes(content, edited) VALUES ('a', '2020-01-01');
+            PRAGMA user_version = 1;").unwrap();
+
+        init_schema(&conn).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), Some(CURRENT_SCHEMA_VERSION));
+        let created: String = conn.query_row("SELECT created FROM nodes",
+            NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(created, "2020-01-01");
+        let (pinned, color): (bool, Option<String>) = conn.query_row(
+            "SELECT pinned, color FROM nodes", NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert!(!pinned);
+        assert_eq!(color, None);
+    }
+
+    #[test]
+    fn foreign_keys() {
+        // tags table without the constraint and with orphaned tags
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+            CREATE TABLE nodes (
+                id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                content TEXT NOT NULL,
+                created DATETIME,
+                edited DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                viewed DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                archived BOOLEAN NOT NULL DEFAULT false,
+                priority INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE tags (
+                node INTEGER NOT NULL,
+                tag text NOT NULL,
+                PRIMARY KEY(node, tag)
+            );
+            INSERT INTO nodes(content) VALUES ('a'), ('b');
+            INSERT INTO tags(node, tag) VALUES (1, 'x'), (2, 'y'), (3, 'z');
+            PRAGMA user_version = 2;").unwrap();
+
+        init_schema(&conn).unwrap();
+        migrate(&conn).unwrap();
+        enable_foreign_keys(&conn).unwrap();
+        assert!(has_foreign_key(&conn, "tags", "nodes").unwrap());
+
+        let count = |conn: &Connection| -> u32 { conn.query_row(
+            "SELECT COUNT(*) FROM tags", NO_PARAMS, |row| row.get(0)).unwrap() };
+        assert_eq!(count(&conn), 2);
+
+        // deleting a node deletes its tags
+        conn.execute_batch("DELETE FROM nodes WHERE id = 1").unwrap();
+        assert_eq!(count(&conn), 1);
+    }
+}
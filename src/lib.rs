@@ -2,6 +2,8 @@ pub mod config;
 pub use config::*;
 
 pub mod pattern;
+pub mod db;
+pub mod query;
 
 #[macro_use]
 extern crate nom;
@@ -1,6 +1,9 @@
 use std::str;
+use std::fmt;
+use std::error;
 
 // Simple recursive tree structure
+#[derive(Clone)]
 pub struct Node<T> {
     pub children: Vec<Node<T>>,
     pub data: T,
@@ -21,18 +24,65 @@ impl<T> Node<T> {
 }
 
 // conditional node
+#[derive(Clone)]
 pub enum CondNodeType {
     Not, // 1 child
     And, // n children
     Or, // n children
+    Xor, // 2 children
     Match(String),
-    ContentMatch(String),
+    ContentMatch(String), // case-insensitive for ascii, like all LIKEs
+    ContentMatchCase(String), // case-sensitive, uses GLOB
     Tag(String),
     TagMatch(String),
+    TagPrefix(String), // e.g. 'proj.' for tags like 'proj.alpha'
+    // dates in YYYY-MM-DD format, compared with the day of the
+    // timestamp. Both bounds exclude the given day itself
+    EditedAfter(String),
+    EditedBefore(String),
+    CreatedAfter(String),
+    CreatedBefore(String),
 }
 
 pub type CondNode = Node<CondNodeType>;
 
+// Escapes the LIKE wildcards in the given string, to be used
+// with ESCAPE '\'.
+fn escape_like(string: &str) -> String {
+    string.replace("\\", "\\\\")
+        .replace("%", "\\%")
+        .replace("_", "\\_")
+}
+
+// Escapes the GLOB special chars in the given string by putting
+// them into brackets.
+fn escape_glob(string: &str) -> String {
+    let mut res = String::new();
+    for c in string.chars() {
+        match c {
+            '*' | '?' | '[' => {
+                res.push('[');
+                res.push(c);
+                res.push(']');
+            }, _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// Combines the given conditions so that all of them must match.
+/// Returns None if there are no conditions.
+pub fn all_of(mut conds: Vec<CondNode>) -> Option<CondNode> {
+    match conds.len() {
+        0 => None,
+        1 => conds.pop(),
+        _ => Some(CondNode {
+            children: conds,
+            data: CondNodeType::And,
+        }),
+    }
+}
+
 // to sql
 pub fn tosql(pattern: &CondNode) -> String {
     let mut query = String::new();
@@ -55,26 +105,44 @@ pub fn tosql(pattern: &CondNode) -> String {
                 }
             }
             query += ")";
+        }, CondNodeType::Xor => {
+            query += &format!("(({}) <> ({}))",
+                tosql(&pattern.children[0]),
+                tosql(&pattern.children[1]));
         }, CondNodeType::ContentMatch(string) => {
-            let escaped = string.replace("'", "''");
-            query += "(content LIKE '%";
-            query += &escaped;
-            query += "%')";
+            let escaped = escape_like(&string.replace("'", "''"));
+            query += &format!("(content LIKE '%{}%' ESCAPE '\\')", &escaped);
+        }, CondNodeType::ContentMatchCase(string) => {
+            let escaped = escape_glob(&string.replace("'", "''"));
+            query += &format!("(content GLOB '*{}*')", &escaped);
         }, CondNodeType::Tag(string) => {
             let escaped = string.replace("'", "''");
             query += &format!("(EXISTS(SELECT 1 FROM tags WHERE
                 node LIKE nodes.id AND tag = '{}'))",
                 &escaped);
         }, CondNodeType::TagMatch(string) => {
-            let escaped = string.replace("'", "''");
+            let escaped = escape_like(&string.replace("'", "''"));
             query += &format!("(EXISTS(SELECT 1 FROM tags WHERE
-                node LIKE nodes.id AND tag LIKE '%{}%'))",
+                node LIKE nodes.id AND tag LIKE '%{}%' ESCAPE '\\'))",
                 &escaped);
+        }, CondNodeType::TagPrefix(string) => {
+            let escaped = escape_like(&string.replace("'", "''"));
+            query += &format!("(EXISTS(SELECT 1 FROM tags WHERE
+                node = nodes.id AND tag LIKE '{}%' ESCAPE '\\'))",
+                &escaped);
+        }, CondNodeType::EditedAfter(date) => {
+            query += &format!("(date(edited) > '{}')", date);
+        }, CondNodeType::EditedBefore(date) => {
+            query += &format!("(date(edited) < '{}')", date);
+        }, CondNodeType::CreatedAfter(date) => {
+            query += &format!("(date(created) > '{}')", date);
+        }, CondNodeType::CreatedBefore(date) => {
+            query += &format!("(date(created) < '{}')", date);
         }, CondNodeType::Match(string) => {
-            let escaped = string.replace("'", "''");
-            query += &format!("(content LIKE '%{0}%' OR
+            let escaped = escape_like(&string.replace("'", "''"));
+            query += &format!("(content LIKE '%{0}%' ESCAPE '\\' OR
                 EXISTS(SELECT 1 FROM tags WHERE
-                node LIKE nodes.id AND tag LIKE '%{0}%'))",
+                node LIKE nodes.id AND tag LIKE '%{0}%' ESCAPE '\\'))",
                 &escaped);
         }
     }
@@ -85,21 +153,54 @@ pub fn tosql(pattern: &CondNode) -> String {
 use nom::types::CompleteStr as Input;
 
 // parser
-named!(value_string_unesc<Input, Input>, is_not!("|&()[]<>/"));
+named!(value_string_unesc<Input, Input>, is_not!("|&^()[]<>/"));
 named!(value_string_esc<Input, Input>,
     delimited!(tag!("\""), is_not!("\""), tag!("\"")));
 named!(value_string<Input, Input>,
     alt_complete!(value_string_esc | value_string_unesc));
 
+// date conditions like 'edited>2023-01-01', the date is validated
+// after parsing, see check_dates
+named!(date_cond<Input, CondNode>, ws!(do_parse!(
+    field: alt_complete!(tag!("edited") | tag!("created")) >>
+    op: alt_complete!(tag!(">") | tag!("<")) >>
+    date: is_not!("|&^()[]<>/ \t") >>
+    (CondNode {
+        children: Vec::new(),
+        data: match (field.0, op.0) {
+            ("edited", ">") => CondNodeType::EditedAfter(date.to_string()),
+            ("edited", _) => CondNodeType::EditedBefore(date.to_string()),
+            (_, ">") => CondNodeType::CreatedAfter(date.to_string()),
+            (_, _) => CondNodeType::CreatedBefore(date.to_string()),
+        }
+    })
+)));
+
 named!(atom<Input, CondNode>, ws!(alt_complete!(
-    // contains full tag
+    date_cond |
+    // contains full tag, or a tag with the given prefix if it ends with '*'
     map!(delimited!(
             tag!("["),
             is_not!("]"),
             tag!("]")),
         |value| CondNode {
             children: Vec::new(),
-            data: CondNodeType::Tag(value.to_string()),
+            data: match value.0.ends_with('*') {
+                true => CondNodeType::TagPrefix(
+                    value.0[..value.0.len() - 1].to_string()),
+                false => CondNodeType::Tag(value.to_string()),
+            }
+    }) |
+    // contains a tag with the given prefix
+    map!(preceded!(
+            tag!("tp"),
+            delimited!(
+                tag!("("),
+                is_not!(")"),
+                tag!(")"))),
+        |value| CondNode {
+            children: Vec::new(),
+            data: CondNodeType::TagPrefix(value.to_string()),
     }) |
     map!(preceded!(
             tag!("t"),
@@ -130,7 +231,18 @@ named!(atom<Input, CondNode>, ws!(alt_complete!(
             children: Vec::new(),
             data: CondNodeType::TagMatch(value.to_string()),
     }) |
-    // contains the given string
+    // contains the given string, case-sensitive
+    map!(preceded!(
+            tag!("C"),
+            delimited!(
+                tag!("("),
+                is_not!(")"),
+                tag!(")"))),
+        |value| CondNode {
+            children: Vec::new(),
+            data: CondNodeType::ContentMatchCase(value.to_string()),
+    }) |
+    // contains the given string, ignoring ascii case
     map!(preceded!(
             tag!("c"),
             delimited!(
@@ -176,8 +288,21 @@ named!(and<Input, CondNode>, ws!(map!(
     }
 )));
 
+// 'a ^ b ^ c' is parsed as '(a ^ b) ^ c'
+named!(xor<Input, CondNode>, ws!(map!(
+    separated_nonempty_list_complete!(tag!("^"), and),
+    |children| {
+        let mut children = children.into_iter();
+        let first = children.next().unwrap();
+        children.fold(first, |acc, next| CondNode {
+            children: vec!(acc, next),
+            data: CondNodeType::Xor,
+        })
+    }
+)));
+
 named!(or<Input, CondNode>, ws!(map!(
-    separated_nonempty_list_complete!(tag!("|"), and),
+    separated_nonempty_list_complete!(tag!("|"), xor),
     |mut children| {
         if children.len() == 1 {
             children.pop().unwrap()
@@ -191,25 +316,202 @@ named!(or<Input, CondNode>, ws!(map!(
 )));
 
 
-pub fn parse_condition(spattern: &str) -> Result<CondNode, String> {
-    let res = or(Input(&spattern));
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Empty, // pattern has no conditions
+    Incomplete, // pattern ended unexpectedly, e.g. unclosed bracket
+    UnexpectedChar { ch: char, pos: usize }, // pos in chars, starting at 0
+    InvalidDate(String), // date condition without YYYY-MM-DD date
+}
 
-    if res.is_err() {
-        // nom::print_error(pattern.as_bytes(), res);
-        return Err("Invalid pattern".to_string());
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Empty pattern"),
+            ParseError::Incomplete => write!(f, "Unexpected end of pattern"),
+            ParseError::UnexpectedChar { ch, pos } => write!(f,
+                "Unexpected character '{}' at position {}", ch, pos + 1),
+            ParseError::InvalidDate(date) => write!(f,
+                "Invalid date '{}', expected YYYY-MM-DD", date),
+        }
     }
+}
 
-    let (rest, value) = res.unwrap();
-    if rest.len() > 0 {
-        Err(format!("Unexpected character {}",
-            rest.chars().next().unwrap()))
-    } else {
-        Ok(value)
+impl error::Error for ParseError {}
+
+// Whether the given string is a date in YYYY-MM-DD format
+fn valid_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 ||
+            parts[2].len() != 2 {
+        return false;
+    }
+
+    match (parts[0].parse::<u32>(), parts[1].parse::<u32>(),
+            parts[2].parse::<u32>()) {
+        (Ok(_), Ok(m), Ok(d)) => m >= 1 && m <= 12 && d >= 1 && d <= 31,
+        _ => false,
+    }
+}
+
+// Makes sure all dates in date conditions are valid
+fn check_dates(node: &CondNode) -> Result<(), ParseError> {
+    match &node.data {
+        CondNodeType::EditedAfter(date) | CondNodeType::EditedBefore(date) |
+        CondNodeType::CreatedAfter(date) | CondNodeType::CreatedBefore(date)
+                if !valid_date(date) =>
+            return Err(ParseError::InvalidDate(date.clone())),
+        _ => (),
+    }
+
+    node.children.iter().map(check_dates).collect()
+}
+
+// Error for the given unparsed rest of the pattern.
+// Opening brackets that are never closed mean the pattern is incomplete.
+fn unexpected(spattern: &str, rest: &str) -> ParseError {
+    let closing = match rest.chars().next() {
+        Some('(') => Some(')'),
+        Some('[') => Some(']'),
+        Some('<') => Some('>'),
+        _ => None,
+    };
+
+    if closing.map_or(false, |c| !rest.contains(c)) {
+        return ParseError::Incomplete;
+    }
+
+    match rest.chars().next() {
+        Some(ch) => {
+            let offset = spattern.len() - rest.len();
+            let pos = spattern[..offset].chars().count();
+            ParseError::UnexpectedChar { ch, pos }
+        }, None => ParseError::Incomplete,
     }
 }
 
-// TODO
+pub fn parse_condition(spattern: &str) -> Result<CondNode, ParseError> {
+    if spattern.trim().is_empty() {
+        return Err(ParseError::Empty);
+    }
+
+    let (rest, value) = match or(Input(&spattern)) {
+        Ok(res) => res,
+        Err(nom::Err::Incomplete(_)) => return Err(ParseError::Incomplete),
+        Err(nom::Err::Error(ctx)) | Err(nom::Err::Failure(ctx)) => {
+            let rest = match ctx {
+                nom::Context::Code(rest, _) => rest,
+                nom::Context::List(list) => list.first()
+                    .map(|(rest, _)| *rest)
+                    .unwrap_or(Input(&spattern)),
+            };
+            return Err(unexpected(spattern, &rest));
+        }
+    };
+
+    if !rest.is_empty() {
+        return Err(unexpected(spattern, &rest));
+    }
+
+    check_dates(&value)?;
+    Ok(value)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+
+    #[test]
+    fn tag_prefix() {
+        for pattern in &["[proj.*]", "tp(proj.)"] {
+            let cond = parse_condition(pattern).unwrap();
+            match &cond.data {
+                CondNodeType::TagPrefix(prefix) => assert_eq!(prefix, "proj."),
+                _ => panic!("{} is not a tag prefix", pattern),
+            }
+        }
+
+        let cond = parse_condition("[a_b*]").unwrap();
+        assert!(tosql(&cond).contains(r"tag LIKE 'a\_b%' ESCAPE '\'"));
+    }
+
+    #[test]
+    fn dates() {
+        let sql = tosql(&parse_condition(
+            "edited>2023-01-01 & created < 2024-06-01").unwrap());
+        assert!(sql.contains("(date(edited) > '2023-01-01')"));
+        assert!(sql.contains("(date(created) < '2024-06-01')"));
+
+        let err = parse_condition("edited>2023-13-01").err().unwrap();
+        assert_eq!(err, ParseError::InvalidDate("2023-13-01".to_string()));
+        assert!(err.to_string().contains("2023-13-01"));
+        assert!(parse_condition("created<yesterday").is_err());
+
+        // still a normal word
+        let cond = parse_condition("edited").unwrap();
+        match cond.data {
+            CondNodeType::Match(word) => assert_eq!(word, "edited"),
+            _ => panic!("edited is not a match"),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_condition("  ").err(), Some(ParseError::Empty));
+        assert_eq!(parse_condition("a & b)").err(),
+            Some(ParseError::UnexpectedChar { ch: ')', pos: 5 }));
+        assert_eq!(parse_condition("ä)").err(),
+            Some(ParseError::UnexpectedChar { ch: ')', pos: 1 }));
+        assert_eq!(parse_condition("a &").err(),
+            Some(ParseError::UnexpectedChar { ch: '&', pos: 2 }));
+        assert_eq!(parse_condition("(a | b").err(), Some(ParseError::Incomplete));
+        assert_eq!(parse_condition("[a").err(), Some(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn xor() {
+        let cond = parse_condition("[work] ^ [urgent]").unwrap();
+        match cond.data {
+            CondNodeType::Xor => assert_eq!(cond.children.len(), 2),
+            _ => panic!("not a xor"),
+        }
+
+        // binds weaker than and, stronger than or
+        let cond = parse_condition("a & b ^ c | d").unwrap();
+        match cond.data {
+            CondNodeType::Or => match cond.children[0].data {
+                CondNodeType::Xor => match cond.children[0].children[0].data {
+                    CondNodeType::And => (),
+                    _ => panic!("and not inside xor"),
+                }, _ => panic!("xor not inside or"),
+            }, _ => panic!("not an or"),
+        }
+
+        let cond = parse_condition("c(a) ^ c(b) ^ c(c)").unwrap();
+        assert_eq!(tosql(&cond), concat!(
+            r"(((((content LIKE '%a%' ESCAPE '\')) <> ",
+            r"((content LIKE '%b%' ESCAPE '\')))) <> ",
+            r"((content LIKE '%c%' ESCAPE '\')))"));
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let sql = tosql(&parse_condition("C(Foo*)").unwrap());
+        assert_eq!(sql, "(content GLOB '*Foo[*]*')");
+        let sql = tosql(&parse_condition("c(Foo*)").unwrap());
+        assert_eq!(sql, r"(content LIKE '%Foo*%' ESCAPE '\')");
+    }
+
+    #[test]
+    fn like_escaping() {
+        let cond = parse_condition("c(50%)").unwrap();
+        assert_eq!(tosql(&cond), r"(content LIKE '%50\%%' ESCAPE '\')");
+
+        let cond = parse_condition("<a_b>").unwrap();
+        assert!(tosql(&cond).contains(r"tag LIKE '%a\_b%' ESCAPE '\'"));
+
+        let sql = tosql(&parse_condition(r"50%\").unwrap());
+        assert!(sql.contains(r"content LIKE '%50\%\\%' ESCAPE '\'"));
+        assert!(sql.contains(r"tag LIKE '%50\%\\%' ESCAPE '\'"));
+    }
 }
@@ -0,0 +1,421 @@
+use rusqlite::{Connection, Row};
+use rusqlite::types::ValueRef;
+use crate::pattern;
+
+#[derive(PartialEq)]
+pub enum Order {
+    Asc,
+    Desc
+}
+
+impl Order {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+
+    pub fn toggle(&self) -> Order {
+        match self {
+            Order::Asc => Order::Desc,
+            Order::Desc => Order::Asc,
+        }
+    }
+}
+
+pub enum Sort {
+    ID,
+    Priority,
+    Edited,
+    Viewed,
+    Created,
+    Random, // ignores the order
+}
+
+impl Sort {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sort::ID => "id",
+            Sort::Priority => "priority",
+            Sort::Edited => "edited",
+            Sort::Viewed => "viewed",
+            Sort::Created => "created",
+            Sort::Random => "random",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Sort> {
+        match name {
+            "id" => Some(Sort::ID),
+            "priority" => Some(Sort::Priority),
+            "edited" => Some(Sort::Edited),
+            "viewed" => Some(Sort::Viewed),
+            "created" => Some(Sort::Created),
+            "random" => Some(Sort::Random),
+            _ => None,
+        }
+    }
+}
+
+/// Which nodes to show based on their archived state.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ArchivedFilter {
+    Active, // only nodes that are not archived
+    Archived, // only archived nodes
+    All, // archived state is ignored
+}
+
+impl ArchivedFilter {
+    /// Parses the filter name. The answers to "archived?" are
+    /// accepted as well: no, yes and both.
+    pub fn parse(name: &str) -> Option<ArchivedFilter> {
+        match name {
+            "active" | "no" => Some(ArchivedFilter::Active),
+            "archived" | "yes" => Some(ArchivedFilter::Archived),
+            "all" | "both" => Some(ArchivedFilter::All),
+            _ => None,
+        }
+    }
+
+    // Returns the sql condition for this filter, None if no
+    // condition is needed.
+    pub fn condition(&self) -> Option<&'static str> {
+        match self {
+            ArchivedFilter::Active => Some("(archived = 0)"),
+            ArchivedFilter::Archived => Some("(archived = 1)"),
+            ArchivedFilter::All => None,
+        }
+    }
+}
+
+/// A queried node, borrowed from the current result row.
+pub struct Node<'a> {
+    pub id: u32,
+    pub priority: i32,
+    pub content: &'a str,
+    pub tags: Vec<&'a str>,
+    pub edited: &'a str,
+    pub pinned: bool,
+    pub color: Option<&'a str>,
+}
+
+/// A queried node that owns its data, see query_nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedNode {
+    pub id: u32,
+    pub priority: i32,
+    pub content: String,
+    pub tags: Vec<String>,
+    pub edited: String,
+    pub pinned: bool,
+    pub color: Option<String>,
+}
+
+impl<'a> From<&Node<'a>> for OwnedNode {
+    fn from(node: &Node<'a>) -> OwnedNode {
+        OwnedNode {
+            id: node.id,
+            priority: node.priority,
+            content: node.content.to_string(),
+            tags: node.tags.iter().map(|t| t.to_string()).collect(),
+            edited: node.edited.to_string(),
+            pinned: node.pinned,
+            color: node.color.map(|c| c.to_string()),
+        }
+    }
+}
+
+/// Which nodes to query and in which order.
+/// The default order is ascending for both orders.
+/// - preorder: the order of nodes before limiting/counting
+/// - postorder: the order of nodes after limiting, i.e. the returned order.
+///   Different pre-/postorders are only relevant if `count` is given.
+/// - count: the maximum number of nodes to retrieve. If not given, all
+/// - pattern: only nodes matching this pattern will be returned
+/// - archived: which nodes to retrieve based on their archived state
+/// - ids: if not none, only nodes with one of these ids will be returned
+/// - pinned_first: whether pinned nodes come before all others in
+///   both orders, regardless of the sort
+pub struct QueryArgs {
+    pub preorder: Order,
+    pub postorder: Order,
+    pub count: Option<usize>,
+    pub pattern: Option<pattern::CondNode>,
+    pub archived: ArchivedFilter,
+    pub sort: Option<Sort>,
+    pub ids: Option<Vec<u32>>,
+    // only fetch the first n chars of the content, e.g. for summaries
+    pub content_prefix: Option<usize>,
+    pub pinned_first: bool,
+}
+
+/// Returns the WHERE clause selecting the nodes matching the given
+/// arguments from the nodes table, empty if all nodes match.
+/// Limit and order are ignored.
+pub fn build_where(args: &QueryArgs) -> String {
+    let mut conds = Vec::new();
+    if let Some(cond) = args.archived.condition() {
+        conds.push(cond.to_string());
+    }
+
+    if let Some(ids) = &args.ids {
+        conds.push(format!("(id {})", in_string(ids)));
+    }
+
+    if let Some(pattern) = &args.pattern {
+        conds.push(pattern::tosql(&pattern));
+    }
+
+    if conds.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conds.join(" AND "))
+    }
+}
+
+/// Returns the sql query for the given arguments.
+/// Selects id, priority, content, the comma-separated tags, edited,
+/// pinned and color.
+pub fn query_string(args: &QueryArgs) -> String {
+    let qwhere = build_where(args);
+    let mut qlimit = String::new();
+    if let Some(count) = args.count {
+        qlimit = format!("LIMIT {}", count);
+    }
+
+    // The sort column is selected as sort_key so that the outer query,
+    // which only sees the selected columns, can order by it as well.
+    // Ties are broken by id to keep the order stable between both.
+    let pin = if args.pinned_first { "pinned DESC, " } else { "" };
+    let mut sort_key = String::new();
+    let mut preorder = String::new();
+    let mut postorder = String::new();
+    if let Some(Sort::Random) = &args.sort {
+        // there is no order to reverse, so the outer query is not needed
+        preorder = format!("ORDER BY {}RANDOM()", pin);
+    } else if let Some(sort) = &args.sort {
+        sort_key = format!(", {} AS sort_key", sort.name());
+        preorder = format!("ORDER BY {pin}sort_key {order}, id {order}",
+            pin = pin, order = args.preorder.name());
+        postorder = format!("ORDER BY {pin}sort_key {order}, id {order}",
+            pin = pin, order = args.postorder.name());
+    } else if args.pinned_first {
+        preorder = "ORDER BY pinned DESC".to_string();
+    }
+
+    let content = match args.content_prefix {
+        Some(n) => format!("substr(content, 1, {})", n),
+        None => "content".to_string(),
+    };
+
+    let mut query = format!("
+        SELECT DISTINCT id, priority, {content}, GROUP_CONCAT(tag), edited,
+            pinned, color {sort_key}
+        FROM nodes
+            LEFT JOIN tags ON nodes.id = tags.node
+        {where}
+        GROUP BY id
+        {order}
+        {limit}",
+        content = content,
+        sort_key = sort_key,
+        where = qwhere,
+        limit = qlimit,
+        order = preorder);
+
+    if !postorder.is_empty() && args.preorder != args.postorder {
+        query = format!("
+            SELECT *
+            FROM ({query})
+            {order}",
+            query = query,
+            order = postorder);
+    }
+
+    query
+}
+
+// Returns the text in the given column, None for NULL
+fn text<'a>(row: &'a Row, i: usize) -> rusqlite::Result<Option<&'a str>> {
+    match row.get_raw_checked(i)? {
+        ValueRef::Null => Ok(None),
+        value => value.as_str().map(Some).map_err(|err|
+            rusqlite::Error::FromSqlConversionFailure(i,
+                value.data_type(), Box::new(err))),
+    }
+}
+
+// Reads a node from a row of the query built by query_string
+fn read_node<'a>(row: &'a Row) -> rusqlite::Result<Node<'a>> {
+    let tags = text(row, 3)?.map(|s| s.split(",").collect());
+    Ok(Node {
+        id: row.get(0)?,
+        priority: row.get(1)?,
+        content: text(row, 2)?.unwrap_or(""),
+        tags: tags.unwrap_or(Vec::new()),
+        edited: text(row, 4)?.unwrap_or(""),
+        pinned: row.get(5)?,
+        color: text(row, 6)?,
+    })
+}
+
+/// Calls op for all nodes matching the given arguments, in order.
+/// Stops at the first error, op may already have been called for
+/// some nodes in that case.
+pub fn iter_nodes<F: FnMut(&Node)>(conn: &Connection,
+        args: &QueryArgs, mut op: F) -> rusqlite::Result<()> {
+    let query = query_string(args);
+    let mut stmt = conn.prepare_cached(&query)?;
+    let mut rows = stmt.query(rusqlite::NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        op(&read_node(row)?);
+    }
+
+    Ok(())
+}
+
+/// Returns the number of nodes matching the given arguments,
+/// ignoring the count limit.
+pub fn count_nodes(conn: &Connection, args: &QueryArgs) -> rusqlite::Result<usize> {
+    let query = format!("SELECT COUNT(*) FROM nodes {}", build_where(args));
+    let count: i64 = conn.query_row(&query, rusqlite::NO_PARAMS, |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+/// Returns all nodes matching the given arguments, in order.
+pub fn query_nodes(conn: &Connection, args: &QueryArgs)
+        -> rusqlite::Result<Vec<OwnedNode>> {
+    let mut nodes = Vec::new();
+    iter_nodes(conn, args, |node| nodes.push(OwnedNode::from(node)))?;
+    Ok(nodes)
+}
+
+/// Returns the sql `in (ids,...)` string for the given ids
+pub fn in_string(ids: &[u32]) -> String {
+    let mut qin = "IN (".to_string();
+    let mut first = true;
+    for id in ids {
+        if !first {
+            qin += ",";
+        }
+        qin += &id.to_string();
+        first = false;
+    }
+
+    qin += ")";
+    qin
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args() -> QueryArgs {
+        QueryArgs {
+            preorder: Order::Asc,
+            postorder: Order::Asc,
+            count: None,
+            pattern: None,
+            archived: ArchivedFilter::Active,
+            sort: Some(Sort::ID),
+            ids: None,
+            content_prefix: None,
+            pinned_first: false,
+        }
+    }
+
+    #[test]
+    fn where_clause() {
+        let mut all = args();
+        all.archived = ArchivedFilter::All;
+        assert_eq!(build_where(&all), "");
+
+        let mut archived = args();
+        archived.archived = ArchivedFilter::Archived;
+        assert_eq!(build_where(&archived), "WHERE (archived = 1)");
+
+        let cond = pattern::parse_condition("foo").unwrap();
+        let sql = pattern::tosql(&cond);
+        let mut pattern = all;
+        pattern.pattern = Some(cond);
+        assert_eq!(build_where(&pattern), format!("WHERE {}", sql));
+
+        let mut combined = args();
+        combined.ids = Some(vec![1, 2]);
+        combined.pattern = pattern.pattern;
+        assert_eq!(build_where(&combined),
+            format!("WHERE (archived = 0) AND (id IN (1,2)) AND {}", sql));
+    }
+
+    #[test]
+    fn query() {
+        let conn = crate::db::open_memory();
+        conn.execute_batch("
+            INSERT INTO nodes(content, created) VALUES ('a', CURRENT_TIMESTAMP);
+            INSERT INTO nodes(content, created) VALUES ('b', CURRENT_TIMESTAMP);
+            INSERT INTO nodes(content, created, archived)
+                VALUES ('c', CURRENT_TIMESTAMP, 1);
+            INSERT INTO tags(node, tag) VALUES (2, 'x');").unwrap();
+
+        let collect = |args: &QueryArgs| {
+            let mut ids = Vec::new();
+            iter_nodes(&conn, args, |node| ids.push(node.id)).unwrap();
+            ids
+        };
+
+        assert_eq!(collect(&args()), vec![1, 2]);
+
+        // newest node first, displayed in ascending order
+        let mut last = args();
+        last.preorder = Order::Desc;
+        last.count = Some(1);
+        last.archived = ArchivedFilter::All;
+        assert_eq!(collect(&last), vec![3]);
+
+        let mut tagged = args();
+        tagged.pattern = Some(pattern::parse_condition("t(x)").unwrap());
+        let mut tags = Vec::new();
+        iter_nodes(&conn, &tagged, |node| tags.push(node.tags.join(","))).unwrap();
+        assert_eq!(tags, vec!["x"]);
+
+        let nodes = query_nodes(&conn, &tagged).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, 2);
+        assert_eq!(nodes[0].content, "b");
+        assert_eq!(nodes[0].tags, vec!["x".to_string()]);
+
+        // pinned nodes come first in both orders
+        conn.execute_batch("UPDATE nodes SET pinned = 1 WHERE id = 1").unwrap();
+        let mut pinned = args();
+        pinned.preorder = Order::Desc;
+        pinned.postorder = Order::Desc;
+        assert_eq!(collect(&pinned), vec![2, 1]);
+        pinned.pinned_first = true;
+        assert_eq!(collect(&pinned), vec![1, 2]);
+        pinned.count = Some(1);
+        pinned.postorder = Order::Asc;
+        assert_eq!(collect(&pinned), vec![1]);
+
+        // counting ignores the limit
+        assert_eq!(count_nodes(&conn, &pinned).unwrap(), 2);
+        assert_eq!(count_nodes(&conn, &tagged).unwrap(), 1);
+
+        // both date bounds exclude the given day itself
+        conn.execute_batch("
+            UPDATE nodes SET edited = '2023-01-01 12:00:00' WHERE id = 1").unwrap();
+        let dated = |cond: &str| {
+            let mut dated = args();
+            dated.pattern = Some(pattern::parse_condition(cond).unwrap());
+            collect(&dated)
+        };
+        assert_eq!(dated("edited>2023-01-01"), vec![2]);
+        assert_eq!(dated("edited<2023-01-01"), Vec::<u32>::new());
+        assert_eq!(dated("edited<2023-01-02"), vec![1]);
+
+        // errors are returned, e.g. when there is no schema
+        let empty = Connection::open_in_memory().unwrap();
+        assert!(iter_nodes(&empty, &args(), |_| ()).is_err());
+        assert!(query_nodes(&empty, &args()).is_err());
+    }
+}
@@ -0,0 +1,676 @@
+// Drives the nodes binary end-to-end against a temporary storage.
+// The config is redirected via XDG_CONFIG_HOME so the user's real
+// config and storages are never touched.
+
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+use tempfile::TempDir;
+
+struct Storage {
+    dir: TempDir,
+}
+
+impl Storage {
+    fn new() -> Storage {
+        let dir = TempDir::new().unwrap();
+
+        let config_dir = dir.path().join("config").join("nodes");
+        fs::create_dir_all(&config_dir).unwrap();
+        let config = format!("[storage]\ntest = {:?}\n",
+            dir.path().to_str().unwrap());
+        fs::write(config_dir.join("config"), config).unwrap();
+
+        // the database itself is created by the first command
+        Storage { dir }
+    }
+
+    fn cmd(&self) -> Command {
+        let mut cmd = Command::cargo_bin("nodes").unwrap();
+        cmd.env("XDG_CONFIG_HOME", self.dir.path().join("config"));
+        cmd
+    }
+
+    fn create(&self, content: &str) -> String {
+        let out = self.cmd().args(&["create", "-c", content])
+            .output().unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    }
+}
+
+#[test]
+fn create_and_ls() {
+    let s = Storage::new();
+    s.cmd().args(&["create", "-c", "first node"])
+        .assert().success().stdout("1\n");
+    s.cmd().args(&["create", "-c", "second node", "-t", "a,b"])
+        .assert().success().stdout("2\n");
+
+    s.cmd().arg("ls").assert().success()
+        .stdout(predicate::str::contains("1:\tfirst node"))
+        .stdout(predicate::str::contains("2:\tsecond node"));
+}
+
+#[test]
+fn create_empty() {
+    let s = Storage::new();
+    s.cmd().args(&["create", "-c", ""]).assert().failure();
+    s.cmd().arg("create").with_stdin().buffer("").assert().failure();
+    s.cmd().arg("ls").assert().success().stdout("");
+
+    // tags given twice are only added once
+    s.cmd().args(&["create", "-c", "twice", "-t", "a,a"]).assert().success();
+    s.cmd().args(&["ls", "--format", "%t"]).assert().success().stdout("a\n");
+}
+
+#[test]
+fn create_stdin() {
+    let s = Storage::new();
+    s.cmd().args(&["create", "-t", "piped"]).with_stdin().buffer("from stdin\nmore\n")
+        .assert().success().stdout("1\n");
+    s.cmd().args(&["output", "1"]).assert().success()
+        .stdout(predicate::str::starts_with("from stdin\nmore\n"));
+    s.cmd().args(&["ls", "--format", "%t"]).assert().success()
+        .stdout("piped\n");
+}
+
+#[test]
+fn tags() {
+    let s = Storage::new();
+    let a = s.create("tagged");
+    let b = s.create("untagged");
+
+    s.cmd().args(&["addtag", "work", &a]).assert().success();
+    s.cmd().args(&["ls", "[work]"]).assert().success()
+        .stdout(predicate::str::contains("tagged"))
+        .stdout(predicate::str::contains("untagged").not());
+
+    s.cmd().args(&["rmtag", "work", &a, &b]).assert().success();
+    s.cmd().args(&["ls", "[work]"]).assert().success().stdout("");
+}
+
+#[test]
+fn archive() {
+    let s = Storage::new();
+    let a = s.create("archived node");
+    s.create("active node");
+
+    s.cmd().args(&["archive", &a]).assert().success();
+    s.cmd().arg("ls").assert().success()
+        .stdout(predicate::str::contains("archived node").not())
+        .stdout(predicate::str::contains("active node"));
+    s.cmd().args(&["ls", "-A"]).assert().success()
+        .stdout(predicate::str::contains("archived node"))
+        .stdout(predicate::str::contains("active node").not());
+    s.cmd().args(&["ls", "--archived", "all"]).assert().success()
+        .stdout(predicate::str::contains("archived node"))
+        .stdout(predicate::str::contains("active node"));
+    s.cmd().args(&["ls", "--archived", "yes"]).assert().success()
+        .stdout(predicate::str::contains("archived node"))
+        .stdout(predicate::str::contains("active node").not());
+    s.cmd().args(&["ls", "--archived", "both"]).assert().success()
+        .stdout(predicate::str::contains("archived node"))
+        .stdout(predicate::str::contains("active node"));
+}
+
+#[test]
+fn ls_total() {
+    let s = Storage::new();
+    for content in &["a", "b", "c"] {
+        s.create(content);
+    }
+
+    s.cmd().args(&["ls", "-n", "2", "--total"]).assert().success()
+        .stdout("2:\tb\n3:\tc\n(showing 2 of 3)\n");
+    s.cmd().args(&["ls", "-n", "3", "--total"]).assert().success()
+        .stdout("1:\ta\n2:\tb\n3:\tc\n");
+    // not written to a terminal
+    s.cmd().args(&["ls", "-n", "2"]).assert().success()
+        .stdout("2:\tb\n3:\tc\n");
+}
+
+#[test]
+fn append() {
+    let s = Storage::new();
+    let a = s.create("list");
+
+    s.cmd().args(&["append", &a, "-c", "- first"]).assert().success();
+    s.cmd().args(&["append", &a]).with_stdin().buffer("- second\n")
+        .assert().success();
+    s.cmd().args(&["output", &a]).assert().success()
+        .stdout("list\n- first\n- second\n\n");
+    s.cmd().args(&["append", "42", "-c", "x"]).assert().failure();
+
+    s.cmd().args(&["prepend", &a, "-c", "# title"]).assert().success();
+    s.cmd().args(&["output", &a]).assert().success()
+        .stdout("# title\nlist\n- first\n- second\n\n");
+}
+
+#[test]
+fn recent() {
+    let s = Storage::new();
+    s.create("first");
+    let b = s.create("second");
+    let c = s.create("third");
+
+    // all were viewed when created, ties are ordered by id
+    s.cmd().args(&["recent", "2"]).assert().success()
+        .stdout(format!("{}:\tsecond\n{}:\tthird\n", b, c));
+}
+
+#[test]
+fn rm() {
+    let s = Storage::new();
+    let a = s.create("first");
+    let b = s.create("second");
+
+    s.cmd().args(&["rm", "-y", &a]).assert().success();
+    s.cmd().arg("ls").assert().success().stdout(format!("{}:\tsecond\n", b));
+
+    // ids read from stdin, there is no one to confirm
+    s.cmd().arg("rm").with_stdin().buffer(format!("{}\n", b))
+        .assert().failure();
+    s.cmd().args(&["rm", "--yes"]).with_stdin().buffer(format!("{}\n", b))
+        .assert().success();
+    s.cmd().arg("ls").assert().success().stdout("");
+}
+
+#[test]
+fn rm_removes_tags() {
+    let s = Storage::new();
+    let a = s.create("first");
+    let b = s.create("second");
+    s.cmd().args(&["addtag", "x", &a, &b]).assert().success();
+    s.cmd().args(&["addtag", "only-a", &a]).assert().success();
+
+    s.cmd().args(&["rm", "-y", &a]).assert().success();
+    s.cmd().arg("tags").assert().success().stdout("x\t1\n");
+}
+
+#[test]
+fn exec() {
+    let s = Storage::new();
+    let a = s.create("first");
+    let b = s.create("second");
+
+    let script = format!("tag {a} work\n# comment\n\npriority {b} +3\narchive {a}\n",
+        a = a, b = b);
+    s.cmd().args(&["exec", "-"]).with_stdin().buffer(script)
+        .assert().success().stdout("line 1: ok\nline 4: ok\nline 5: ok\n");
+    s.cmd().args(&["ls", "-a", "[work]"]).assert().success()
+        .stdout(format!("{}:\tfirst\n", a));
+    s.cmd().args(&["ls", "-s", "priority", "-n", "1"]).assert().success()
+        .stdout(format!("{}:\tsecond\n", b));
+
+    // invalid lines abort the whole batch
+    let script = format!("rm {}\nfrobnicate\n", b);
+    s.cmd().args(&["exec", "-"]).with_stdin().buffer(script)
+        .assert().failure()
+        .stdout("")
+        .stderr(predicate::str::starts_with("line 2: "))
+        .stderr(predicate::str::ends_with("1 invalid commands, no changes applied\n"));
+    s.cmd().arg("ls").assert().success()
+        .stdout(predicate::str::contains("second"));
+}
+
+#[test]
+fn tags_merge() {
+    let s = Storage::new();
+    let a = s.create("first");
+    let b = s.create("second");
+
+    s.cmd().args(&["addtag", "work", &a, &b]).assert().success();
+    s.cmd().args(&["addtag", "job", &b]).assert().success();
+    s.cmd().args(&["tags", "merge", "work", "job"]).assert().success();
+    s.cmd().arg("tags").assert().success().stdout("job\t2\n");
+}
+
+#[test]
+fn tags_rename() {
+    let s = Storage::new();
+    let a = s.create("first");
+    let b = s.create("second");
+    let c = s.create("third");
+
+    // b has both tags, it must not end up with a duplicate
+    s.cmd().args(&["addtag", "todo", &a, &b]).assert().success();
+    s.cmd().args(&["addtag", "to-do", &b, &c]).assert().success();
+    s.cmd().args(&["tag", "rename", "to-do", "todo"]).assert().success();
+    s.cmd().arg("tags").assert().success().stdout("todo\t3\n");
+    s.cmd().args(&["ls", "--format", "%t", "--tag", "todo"]).assert().success()
+        .stdout("todo\ntodo\ntodo\n");
+}
+
+#[test]
+fn ls_print0() {
+    let s = Storage::new();
+    s.create("first");
+    s.create("second");
+
+    s.cmd().args(&["ls", "--print0"]).assert().success().stdout("1\x002\x00");
+    s.cmd().args(&["ls", "-0", "second"]).assert().success().stdout("2\x00");
+    s.cmd().args(&["ls", "-0", "--format", "%s"]).assert().success()
+        .stdout("first\x00second\x00");
+    s.cmd().args(&["ls", "-0", "--full"]).assert().failure();
+}
+
+#[test]
+fn ls_group_by_tag() {
+    let s = Storage::new();
+    let a = s.create("a");
+    let b = s.create("b");
+    let c = s.create("c");
+
+    s.cmd().args(&["addtag", "x", &a, &b]).assert().success();
+    s.cmd().args(&["addtag", "w", &b]).assert().success();
+
+    s.cmd().args(&["ls", "--group-by", "tag"]).assert().success()
+        .stdout(format!("[w]\n{b}:\tb\n\n[x]\n{a}:\ta\n{b}:\tb\n", a = a, b = b));
+    s.cmd().args(&["ls", "--group-by", "tag", "--untagged"]).assert().success()
+        .stdout(predicate::str::ends_with(format!("\n\n[untagged]\n{}:\tc\n", c)));
+}
+
+#[test]
+fn quoted_tags() {
+    let s = Storage::new();
+    let a = s.create("a");
+
+    let tag = r#"weird'"\name"#;
+    s.cmd().args(&["addtag", tag, &a]).assert().success();
+    s.cmd().arg("tags").assert().success().stdout(format!("{}\t1\n", tag));
+
+    s.cmd().args(&["rmtag", tag, &a]).assert().success();
+    s.cmd().arg("tags").assert().success().stdout("");
+}
+
+#[test]
+fn export() {
+    let s = Storage::new();
+    let a = s.create("first\nnode");
+    let b = s.create("second");
+    s.cmd().args(&["addtag", "x", &a]).assert().success();
+    s.cmd().args(&["archive", &b]).assert().success();
+
+    let out = s.cmd().arg("export").output().unwrap();
+    assert!(out.status.success());
+    let nodes: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let nodes = nodes.as_array().unwrap();
+    assert_eq!(nodes.len(), 2);
+    assert_eq!(nodes[0]["id"].to_string(), a);
+    assert_eq!(nodes[0]["content"], "first\nnode");
+    assert_eq!(nodes[0]["tags"], serde_json::json!(["x"]));
+    assert_eq!(nodes[1]["archived"], true);
+
+    let out = s.cmd().args(&["export", "[x]"]).output().unwrap();
+    let nodes: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(nodes.as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn import() {
+    let a = Storage::new();
+    a.create("first");
+    let second = a.create("second");
+    a.cmd().args(&["addtag", "x", &second]).assert().success();
+    let out = a.cmd().arg("export").output().unwrap();
+    assert!(out.status.success());
+
+    let b = Storage::new();
+    b.create("existing");
+    b.cmd().arg("import").with_stdin().buffer(out.stdout.clone())
+        .assert().success().stderr("Imported 2 nodes\n");
+    b.cmd().args(&["ls", "[x]"]).assert().success().stdout("3:\tsecond\n");
+
+    // id 1 is taken, nothing is imported
+    b.cmd().args(&["import", "--preserve-ids"]).with_stdin().buffer(out.stdout)
+        .assert().failure().stderr("Node 1 already exists\n");
+    b.cmd().arg("ls").assert().success()
+        .stdout(predicate::str::contains("4:").not());
+
+    b.cmd().arg("import").with_stdin().buffer("[{\"id\": 1}]").assert().failure();
+}
+
+#[test]
+fn ndjson() {
+    let a = Storage::new();
+    a.create("first\nnode");
+    let second = a.create("second");
+    a.cmd().args(&["addtag", "x", &second]).assert().success();
+
+    let out = a.cmd().args(&["export", "--format", "ndjson"]).output().unwrap();
+    assert!(out.status.success());
+    let out = String::from_utf8(out.stdout).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let node: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(node["id"].to_string(), second);
+    assert_eq!(node["tags"], serde_json::json!(["x"]));
+
+    let b = Storage::new();
+    b.cmd().arg("import").with_stdin().buffer(out)
+        .assert().success().stderr("Imported 2 nodes\n");
+    b.cmd().args(&["output", "1"]).assert().success().stdout("first\nnode\n");
+    b.cmd().args(&["ls", "[x]"]).assert().success().stdout("2:\tsecond\n");
+
+    // nothing is imported if one of the lines is invalid
+    b.cmd().arg("import").with_stdin().buffer("{\"id\": 1}\n{\"id\": 2")
+        .assert().failure();
+    b.cmd().arg("ls").assert().success()
+        .stdout(predicate::str::contains("3:").not());
+}
+
+#[test]
+fn default_sort() {
+    let s = Storage::new();
+    let config = format!("[storage.test]\npath = {:?}\ndefault_sort = \"priority\"\n",
+        s.dir.path().to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+
+    let a = s.create("a");
+    let b = s.create("b");
+    s.cmd().args(&["priority", "5", &a]).assert().success();
+    s.cmd().args(&["ls"]).assert().success()
+        .stdout(format!("{}:\tb\n{}:\ta\n", b, a));
+    s.cmd().args(&["ls", "-s", "id"]).assert().success()
+        .stdout(format!("{}:\ta\n{}:\tb\n", a, b));
+
+    // invalid sorts are rejected when loading the config
+    let config = format!("[storage.test]\npath = {:?}\ndefault_sort = \"size\"\n",
+        s.dir.path().to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+    s.cmd().arg("ls").assert().failure();
+
+    let config = format!("[storage.test]\npath = {:?}\ndefault_sort = \"id\"\n\
+        [storage.test.defaults]\nsort = \"priority\"\n",
+        s.dir.path().to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+    s.cmd().arg("ls").assert().failure()
+        .stderr(predicate::str::contains("both default_sort and defaults.sort"));
+}
+
+#[test]
+fn storage_path_missing() {
+    let s = Storage::new();
+    let file = s.dir.path().join("file");
+    fs::write(&file, "").unwrap();
+    let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\nbroken = {:?}\n",
+        s.dir.path().to_str().unwrap(), file.join("sub").to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+
+    s.cmd().arg("ls").assert().failure()
+        .stderr(predicate::str::contains("storage 'broken'"));
+
+    // missing folders are created once the storage is used
+    let config = format!("[storage]\ntest = {:?}\n",
+        s.dir.path().join("new").to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+    s.cmd().args(&["config", "check"]).assert().success();
+    s.cmd().arg("storages").assert().success();
+    assert!(!s.dir.path().join("new").exists());
+    s.cmd().arg("ls").assert().success();
+    assert!(s.dir.path().join("new").join("nodes.db").is_file());
+
+    // the same goes for the default storage without config file
+    fs::remove_file(s.dir.path().join("config").join("nodes").join("config"))
+        .unwrap();
+    let data = s.dir.path().join("data");
+    s.cmd().env("XDG_DATA_HOME", &data).args(&["config", "check"])
+        .assert().success();
+    s.cmd().env("XDG_DATA_HOME", &data).arg("storages").assert().success();
+    assert!(!data.exists());
+    s.cmd().env("XDG_DATA_HOME", &data).arg("ls").assert().success();
+    assert!(data.join("nodes").join("nodes.db").is_file());
+}
+
+#[test]
+fn storages() {
+    let s = Storage::new();
+    let other = s.dir.path().join("other");
+    let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\nother = {:?}\n",
+        s.dir.path().to_str().unwrap(), other.to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+
+    s.create("a");
+    s.cmd().arg("storages").assert().success().stdout(format!(
+        "  other\t{}/other (no database yet)\n* test\t{}\n",
+        s.dir.path().display(), s.dir.path().display()));
+}
+
+#[test]
+fn programs_only_config() {
+    let s = Storage::new();
+    fs::write(s.dir.path().join("config").join("nodes").join("config"),
+        "[programs]\neditor = [\"true\"]\n").unwrap();
+
+    // the default storage is used
+    let data = s.dir.path().join("data");
+    s.cmd().env("XDG_DATA_HOME", &data).arg("storages").assert().success()
+        .stdout(predicate::str::starts_with("* default\t"));
+    s.cmd().env("XDG_DATA_HOME", &data).args(&["create", "-c", "a"])
+        .assert().success().stdout("1\n");
+    assert!(data.join("nodes").join("nodes.db").is_file());
+}
+
+#[test]
+fn config_check() {
+    let s = Storage::new();
+    let path = s.dir.path().join("config").join("nodes").join("config");
+    s.cmd().args(&["config", "path"]).assert().success()
+        .stdout(format!("{}\n", path.display()));
+    s.cmd().args(&["config", "check"]).assert().success()
+        .stdout(predicate::str::contains(format!("* test\t{} (no database yet)",
+            s.dir.path().display())))
+        .stdout(predicate::str::contains("none configured"));
+
+    fs::write(&path, "[storage]\ndefault = \"nope\"\ntest = \"/tmp\"\n").unwrap();
+    s.cmd().args(&["config", "check"]).assert().failure()
+        .stderr(predicate::str::contains("storage.default must be"));
+    // the path is still printed for invalid configs
+    s.cmd().args(&["config", "path"]).assert().success();
+
+    // synchronous is the sqlite mode, not a storage
+    fs::write(&path, "[storage]\ndefault = \"test\"\ntest = \"/tmp\"\n\
+        synchronous = \"/tmp/sync\"\n").unwrap();
+    s.cmd().args(&["config", "check"]).assert().failure()
+        .stderr(predicate::str::contains("Storages can't be named 'synchronous'"));
+    fs::write(&path, "[storage]\ntest = \"/tmp\"\nsynchronous = \"full\"\n")
+        .unwrap();
+    s.cmd().args(&["config", "check"]).assert().success();
+
+    // parse errors point to the position, also for other commands
+    fs::write(&path, "[storage\n").unwrap();
+    s.cmd().arg("ls").assert().failure()
+        .stderr(predicate::str::contains(format!("{}:1:9\n", path.display())));
+}
+
+#[test]
+fn duplicate_storage_path() {
+    let s = Storage::new();
+    let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\ncopy = \"{}/.\"\n",
+        s.dir.path().to_str().unwrap(), s.dir.path().display());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+
+    s.cmd().arg("ls").assert().failure()
+        .stderr(predicate::str::contains("Storages 'copy' and 'test' both use"));
+
+    // folders that don't exist yet, given relative and absolute
+    let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\n\
+        a = \"{}/new\"\nb = \"new/\"\n",
+        s.dir.path().to_str().unwrap(), s.dir.path().display());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+    s.cmd().current_dir(s.dir.path()).arg("ls").assert().failure()
+        .stderr(predicate::str::contains("Storages 'a' and 'b' both use"));
+    assert!(!s.dir.path().join("new").exists());
+
+    // ".." is applied after resolving the symlink
+    #[cfg(unix)] {
+        let target = s.dir.path().join("x").join("y");
+        fs::create_dir_all(&target).unwrap();
+        std::os::unix::fs::symlink(&target, s.dir.path().join("link")).unwrap();
+        let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\n\
+            a = \"{}/x\"\nb = \"{}/link/..\"\n",
+            s.dir.path().to_str().unwrap(), s.dir.path().display(),
+            s.dir.path().display());
+        fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+            .unwrap();
+        s.cmd().arg("ls").assert().failure()
+            .stderr(predicate::str::contains("Storages 'a' and 'b' both use"));
+    }
+
+    let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\n\
+        Test = \"{}/other\"\n",
+        s.dir.path().to_str().unwrap(), s.dir.path().display());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+    s.cmd().arg("ls").assert().failure()
+        .stderr(predicate::str::contains("'Test' and 'test' only differ in case"));
+}
+
+#[test]
+fn move_nodes() {
+    let s = Storage::new();
+    let other = s.dir.path().join("other");
+    fs::create_dir(&other).unwrap();
+    let config = format!("[storage]\ndefault = \"test\"\ntest = {:?}\nother = {:?}\n",
+        s.dir.path().to_str().unwrap(), other.to_str().unwrap());
+    fs::write(s.dir.path().join("config").join("nodes").join("config"), config)
+        .unwrap();
+
+    s.cmd().args(&["-s", "other", "create", "-c", "existing"])
+        .assert().success().stdout("1\n");
+    let a = s.create("a");
+    let b = s.create("b");
+    s.cmd().args(&["addtag", "x", &b]).assert().success();
+
+    s.cmd().args(&["move", "--to", "other", &b]).assert().success().stdout("2\n");
+    s.cmd().arg("ls").assert().success().stdout(format!("{}:\ta\n", a));
+    s.cmd().args(&["-s", "other", "ls", "[x]"]).assert().success()
+        .stdout("2:\tb\n");
+
+    s.cmd().args(&["move", "--to", "test", &a]).assert().failure();
+    s.cmd().args(&["move", "--to", "unknown", &a]).assert().failure();
+
+    s.cmd().args(&["move", "--to", "other", &a, &a]).assert().success()
+        .stdout("3\n");
+}
+
+#[test]
+fn rm_dry_run() {
+    let s = Storage::new();
+    let a = s.create("a");
+    let b = s.create("b");
+
+    s.cmd().args(&["rm", "--dry-run", &a, &b]).assert().success()
+        .stdout(format!("{}:\ta\n{}:\tb\n", a, b));
+    s.cmd().arg("ls").assert().success()
+        .stdout(format!("{}:\ta\n{}:\tb\n", a, b));
+}
+
+#[test]
+fn output_multiple() {
+    let s = Storage::new();
+    let a = s.create("a");
+    let b = s.create("b");
+
+    s.cmd().args(&["output", &a, &b]).assert().success()
+        .stdout("a\n---\nb\n");
+    s.cmd().args(&["output", "--raw", "-d", "|"])
+        .with_stdin().buffer(format!("{}\n{}\n", b, a))
+        .assert().success().stdout("b|a");
+    s.cmd().args(&["output", &a, "99"]).assert().failure().stdout("a\n");
+}
+
+#[test]
+fn output_meta() {
+    let s = Storage::new();
+    let a = s.create("content");
+    s.cmd().args(&["addtag", "x", &a]).assert().success();
+
+    s.cmd().args(&["output", "--meta", &a]).assert().success()
+        .stdout(predicate::str::starts_with(format!("id:       {}\n", a)))
+        .stdout(predicate::str::contains("tags:     x\n"))
+        .stdout(predicate::str::ends_with("\n\ncontent\n"));
+}
+
+#[test]
+fn ls_format() {
+    let s = Storage::new();
+    s.create("first\nmore");
+    s.cmd().args(&["create", "-c", "second", "-t", "a,b"])
+        .assert().success();
+    s.cmd().args(&["exec", "-"]).with_stdin().buffer("priority 2 +2\n")
+        .assert().success();
+
+    s.cmd().args(&["ls", "--format", "%i,%p,%t,%s"])
+        .assert().success()
+        .stdout("1,0,,first\n2,2,a,b,second\n");
+}
+
+#[test]
+fn ls_tag_filter() {
+    let s = Storage::new();
+    s.cmd().args(&["create", "-c", "both", "-t", "work,urgent"]).assert().success();
+    s.cmd().args(&["create", "-c", "work only", "-t", "work"]).assert().success();
+    s.cmd().args(&["create", "-c", "urgent other", "-t", "urgent"]).assert().success();
+
+    s.cmd().args(&["ls", "--format", "%i", "--tag", "work"])
+        .assert().success().stdout("1\n2\n");
+    s.cmd().args(&["ls", "--format", "%i", "--tag", "work", "--tag", "urgent"])
+        .assert().success().stdout("1\n");
+    s.cmd().args(&["ls", "--format", "%i", "--tag", "urgent", "c(other)"])
+        .assert().success().stdout("3\n");
+}
+
+#[test]
+fn memory() {
+    let s = Storage::new();
+    s.cmd().args(&["--memory", "create", "-c", "gone"])
+        .assert().success().stdout("1\n");
+    s.cmd().args(&["--memory", "ls"]).assert().success().stdout("");
+    s.cmd().arg("ls").assert().success().stdout("");
+    s.cmd().args(&["--memory", "-s", "test", "ls"]).assert().failure();
+}
+
+#[test]
+fn priority() {
+    let s = Storage::new();
+    s.create("a");
+    s.create("b");
+    s.create("c");
+
+    s.cmd().args(&["priority", "-3", "1", "3"]).assert().success();
+    s.cmd().arg("priority").arg("7").with_stdin().buffer("2\n")
+        .assert().success();
+    s.cmd().args(&["ls", "--format", "%i %p"]).assert().success()
+        .stdout("1 -3\n2 7\n3 -3\n");
+
+    s.cmd().args(&["priority", "99999999999", "1"]).assert().failure();
+}
+
+#[test]
+fn null_separated_ids() {
+    let s = Storage::new();
+    s.create("a");
+    s.create("b");
+    s.create("c");
+
+    s.cmd().args(&["archive", "-z"]).with_stdin().buffer("1\u{0}3\u{0}")
+        .assert().success();
+    s.cmd().args(&["ls", "--format", "%i"]).assert().success().stdout("2\n");
+
+    // the default is still one id per line
+    s.cmd().arg("archive").with_stdin().buffer("1\n3\n")
+        .assert().success();
+    s.cmd().args(&["ls", "--format", "%i"]).assert().success().stdout("1\n2\n3\n");
+}